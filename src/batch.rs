@@ -0,0 +1,41 @@
+use crate::command::Command;
+
+/// A group of sets and removes that `KvStore::write` applies atomically.
+///
+/// Operations are applied in the order they were added.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("from".to_owned(), "10".to_owned()).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove("from".to_owned());
+/// batch.set("to".to_owned(), "10".to_owned());
+/// store.write(batch).unwrap();
+/// assert_eq!(store.get("to".to_owned()).unwrap(), Some("10".to_owned()));
+/// ```
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a string key to a string when the batch is written.
+    pub fn set(&mut self, key: String, value: String) {
+        self.commands.push(Command::Set(key, value.into_bytes()));
+    }
+
+    /// Removes a given key when the batch is written.
+    pub fn remove(&mut self, key: String) {
+        self.commands.push(Command::Rm(key));
+    }
+}
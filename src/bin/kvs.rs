@@ -1,7 +1,14 @@
 use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::KvStore;
+use failure::format_err;
+use kvs::{KvStore, KvsEngine, MemoryKvsEngine, Result, SledKvsEngine};
+use std::fs;
+use std::io;
 use std::process::exit;
 
+// Records which engine wrote the data in the current directory
+const ENGINE_FILE: &str = "engine";
+const DEFAULT_ENGINE: &str = "kvs";
+
 fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -10,6 +17,14 @@ fn main() {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("The storage engine, defaults to the one that wrote the existing data")
+                .possible_values(&["kvs", "sled", "memory"])
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -32,7 +47,13 @@ fn main() {
         )
         .get_matches();
 
-    let mut store = KvStore::open(".").expect("open error");
+    let store = match open_engine(matches.value_of("engine")) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
 
     match matches.subcommand() {
         ("set", Some(_matches)) => {
@@ -80,3 +101,58 @@ fn main() {
         _ => unreachable!(),
     }
 }
+
+/// Opens the requested engine in the current directory, refusing to open data that
+/// was written by a different one.
+fn open_engine(requested: Option<&str>) -> Result<Box<dyn KvsEngine>> {
+    // Nothing is read from or written to the directory
+    if requested == Some("memory") {
+        return Ok(Box::new(MemoryKvsEngine::new()));
+    }
+
+    let recorded = match fs::read_to_string(ENGINE_FILE) {
+        Ok(engine) => Some(engine),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    // Data written before the engine was recorded can only come from kvs
+    let current = match recorded.clone() {
+        Some(engine) => Some(engine),
+        None => has_kvs_logs()?.then(|| DEFAULT_ENGINE.to_owned()),
+    };
+    let engine = match (requested, current.as_deref()) {
+        (Some(requested), Some(current)) if requested != current => {
+            return Err(format_err!(
+                "data was written by the {} engine, cannot open it with {}",
+                current,
+                requested
+            ));
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => DEFAULT_ENGINE,
+    };
+    if recorded.is_none() {
+        fs::write(ENGINE_FILE, engine)?;
+    }
+
+    match engine {
+        "kvs" => Ok(Box::new(KvStore::open(".")?)),
+        "sled" => Ok(Box::new(SledKvsEngine::open(".")?)),
+        _ => Err(format_err!("unknown engine {}", engine)),
+    }
+}
+
+/// Whether the current directory holds log files written by `KvStore`.
+fn has_kvs_logs() -> Result<bool> {
+    for entry in fs::read_dir(".")? {
+        let file_name = entry?.file_name();
+        let is_log = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .is_some_and(|stem| stem == "head" || stem.parse::<u64>().is_ok());
+        if is_log {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
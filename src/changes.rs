@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+use crate::{Event, KvStore, Result, command::Command, log};
+
+/// A write committed to a `KvStore`, as returned by `Changes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Sequence number of the write, greater than those of the writes before it.
+    pub seq: u64,
+    /// What the write did.
+    pub event: Event,
+}
+
+/// Iterator over the writes committed to a `KvStore`, in commit order, as read
+/// from its log.
+///
+/// It yields the writes numbered from `cursor` on, and returns `None` once it has
+/// caught up with the log. Calling `next` again later picks up the writes committed
+/// since. The cursor stays valid across compactions and restarts, so a reader can
+/// save it and resume with `KvStore::changes`.
+///
+/// Compaction only keeps the latest write of each key, and eventually drops
+/// removes: a reader that falls behind it skips the writes that were dropped. Writes
+/// made by versions of the store that did not number them are not reported.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{Event, KvStore, KvsEngine};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key".to_owned(), "value".to_owned()).unwrap();
+///
+/// let mut changes = store.changes(0);
+/// let change = changes.next().unwrap().unwrap();
+/// assert_eq!(change.event, Event::Set("key".to_owned(), b"value".to_vec()));
+/// assert!(changes.next().is_none());
+///
+/// store.remove("key".to_owned()).unwrap();
+/// let mut changes = store.changes(changes.cursor());
+/// let change = changes.next().unwrap().unwrap();
+/// assert_eq!(change.event, Event::Removed("key".to_owned()));
+/// ```
+pub struct Changes {
+    store: KvStore,
+    cursor: u64,
+    // Segment `pending` was read from, where reading resumes
+    generation: Option<u64>,
+    pending: VecDeque<Change>,
+}
+
+impl Changes {
+    pub(crate) fn new(store: KvStore, cursor: u64) -> Changes {
+        Changes {
+            store,
+            cursor,
+            generation: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Sequence number to resume reading from: the one following the last write
+    /// returned.
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Reads the writes following the cursor from the first segment that has any.
+    fn fill(&mut self) -> Result<()> {
+        let generations = self.store.generations();
+        let start = match self.generation {
+            Some(generation) => generation,
+            None => self.find_start(&generations)?,
+        };
+        for &generation in generations
+            .iter()
+            .filter(|&&generation| generation >= start)
+        {
+            let cursor = self.cursor;
+            let pending = &mut self.pending;
+            log::read_segment(self.store.dir_path(), generation, |record_type, payload| {
+                if let (cmd, Some(seq)) = Command::decode_with_seq(record_type, payload)?
+                    && seq >= cursor
+                {
+                    pending.push_back(Change {
+                        seq,
+                        event: Event::from_command(&cmd),
+                    });
+                }
+                Ok(true)
+            })?;
+            self.generation = Some(generation);
+            if !self.pending.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the segment the write numbered `cursor` would be in: the last one whose
+    /// first write is not after it.
+    fn find_start(&self, generations: &[u64]) -> Result<u64> {
+        for &generation in generations.iter().rev() {
+            let mut first_seq = None;
+            log::read_segment(self.store.dir_path(), generation, |record_type, payload| {
+                first_seq = Some(Command::decode_with_seq(record_type, payload)?.1);
+                Ok(false)
+            })?;
+            match first_seq {
+                None => continue,
+                Some(Some(seq)) if seq > self.cursor => continue,
+                // Writes without a sequence number are older than any with one
+                Some(_) => return Ok(generation),
+            }
+        }
+        Ok(generations.first().copied().unwrap_or(0))
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        if self.pending.is_empty()
+            && let Err(err) = self.fill()
+        {
+            return Some(Err(err));
+        }
+        let change = self.pending.pop_front()?;
+        self.cursor = change.seq + 1;
+        Some(Ok(change))
+    }
+}
@@ -0,0 +1,230 @@
+//! Encoding of the writes recorded in the log.
+//!
+//! A `Command` is the payload of a `RecordType::Command` record, laid out as:
+//!
+//! ```text
+//! +---------+---------------+-----+-----------------+-------+
+//! | op (u8) | key len (u32) | key | value len (u32) | value |
+//! +---------+---------------+-----+-----------------+-------+
+//! ```
+//!
+//! Integers are little endian. A remove has no value, and a set that expires is
+//! followed by its deadline, in milliseconds since the Unix epoch (u64). Values are
+//! stored as they are, without any escaping. Last comes the sequence number of the
+//! write (u64), which records written before sequence numbers were introduced lack.
+//!
+//! Logs written by older versions hold `RecordType::JsonCommand` records instead,
+//! which are still read.
+
+use failure::format_err;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    Result,
+    record::{RecordType, take},
+};
+
+const OP_SET: u8 = 1;
+const OP_RM: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+
+/// A write to one key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    Set(String, Vec<u8>),
+    Rm(String),
+    /// A set whose value expires at the given deadline, in milliseconds since the
+    /// Unix epoch.
+    SetExpiring(String, Vec<u8>, u64),
+}
+
+/// A `Command` as older versions serialized it, with string values only.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd", content = "params")]
+enum JsonCommand {
+    Set(String, String),
+    Rm(String),
+    SetExpiring(String, String, u64),
+}
+
+impl Command {
+    /// Key the command writes.
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Command::Set(key, _) | Command::Rm(key) | Command::SetExpiring(key, _, _) => key,
+        }
+    }
+
+    /// Builds the payload of the `RecordType::Command` record holding the command,
+    /// the write numbered `seq`.
+    pub(crate) fn encode(&self, seq: u64) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        let (op, key, value) = match self {
+            Command::Set(key, value) => (OP_SET, key, Some(value)),
+            Command::Rm(key) => (OP_RM, key, None),
+            Command::SetExpiring(key, value, _) => (OP_SET_EXPIRING, key, Some(value)),
+        };
+        payload.push(op);
+        put_bytes(&mut payload, key.as_bytes())?;
+        if let Some(value) = value {
+            put_bytes(&mut payload, value)?;
+        }
+        if let Command::SetExpiring(_, _, expires_at) = self {
+            payload.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        payload.extend_from_slice(&seq.to_le_bytes());
+        Ok(payload)
+    }
+
+    /// Parses the payload of a record of type `record_type`.
+    pub(crate) fn decode(record_type: RecordType, payload: &[u8]) -> Result<Command> {
+        Ok(Command::decode_with_seq(record_type, payload)?.0)
+    }
+
+    /// Parses the payload of a record of type `record_type`, along with the
+    /// sequence number of the write if it has one.
+    pub(crate) fn decode_with_seq(
+        record_type: RecordType,
+        payload: &[u8],
+    ) -> Result<(Command, Option<u64>)> {
+        match record_type {
+            RecordType::Command => decode_binary(payload),
+            RecordType::JsonCommand => Ok((
+                match serde_json::from_slice(payload)? {
+                    JsonCommand::Set(key, value) => Command::Set(key, value.into_bytes()),
+                    JsonCommand::Rm(key) => Command::Rm(key),
+                    JsonCommand::SetExpiring(key, value, expires_at) => {
+                        Command::SetExpiring(key, value.into_bytes(), expires_at)
+                    }
+                },
+                None,
+            )),
+            _ => Err(format_err!("unexpected {:?} record", record_type)),
+        }
+    }
+}
+
+fn decode_binary(mut payload: &[u8]) -> Result<(Command, Option<u64>)> {
+    let [op] = take(&mut payload)?;
+    let key = String::from_utf8(take_bytes(&mut payload)?.to_vec())?;
+    let cmd = match op {
+        OP_SET => Command::Set(key, take_bytes(&mut payload)?.to_vec()),
+        OP_RM => Command::Rm(key),
+        OP_SET_EXPIRING => {
+            let value = take_bytes(&mut payload)?.to_vec();
+            Command::SetExpiring(key, value, u64::from_le_bytes(take(&mut payload)?))
+        }
+        _ => return Err(format_err!("unknown command op {}", op)),
+    };
+    let seq = match payload {
+        [] => None,
+        _ => Some(u64::from_le_bytes(take(&mut payload)?)),
+    };
+    if !payload.is_empty() {
+        return Err(format_err!("trailing bytes after command"));
+    }
+    Ok((cmd, seq))
+}
+
+/// Checks whether `prefix`, the start of a command payload, agrees with the payload
+/// being `len` bytes long, as far as it goes.
+///
+/// A torn write leaves such a prefix behind, while a damaged length field rarely
+/// matches the lengths the payload records itself.
+pub(crate) fn prefix_fits(mut prefix: &[u8], len: u64) -> bool {
+    let Ok([op]) = take(&mut prefix) else {
+        return true;
+    };
+    if !matches!(op, OP_SET | OP_RM | OP_SET_EXPIRING) {
+        return false;
+    }
+    let Ok(key_len) = take(&mut prefix).map(u32::from_le_bytes) else {
+        return true;
+    };
+    // Op, key length and key
+    let mut end = 5 + u64::from(key_len);
+    if op != OP_RM {
+        let Some(rest) = prefix.get(key_len as usize..) else {
+            return end + 4 <= len;
+        };
+        prefix = rest;
+        let Ok(value_len) = take(&mut prefix).map(u32::from_le_bytes) else {
+            return end + 4 <= len;
+        };
+        end += 4 + u64::from(value_len);
+        if op == OP_SET_EXPIRING {
+            end += 8;
+        }
+    }
+    // With or without a sequence number
+    len == end || len == end + 8
+}
+
+/// Appends `bytes` preceded by their length.
+fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len: u32 = bytes
+        .len()
+        .try_into()
+        .map_err(|_| format_err!("command too large: {} bytes", bytes.len()))?;
+    payload.extend_from_slice(&len.to_le_bytes());
+    payload.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Splits bytes written by `put_bytes` off `buf`.
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = u32::from_le_bytes(take(buf)?) as usize;
+    if buf.len() < len {
+        return Err(format_err!("command truncated"));
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, JsonCommand, prefix_fits};
+    use crate::record::RecordType;
+
+    #[test]
+    fn test_serialize() {
+        let set_cmd = JsonCommand::Set("key".to_string(), "value".to_string());
+        let rm_cmd = JsonCommand::Rm("key".to_string());
+        let json_data = serde_json::to_string(&set_cmd).expect("marshal failed");
+        assert_eq!(json_data, r#"{"cmd":"Set","params":["key","value"]}"#);
+        let json_data = serde_json::to_string(&rm_cmd).expect("marshal failed");
+        assert_eq!(json_data, r#"{"cmd":"Rm","params":"key"}"#);
+
+        let cmd =
+            Command::decode(RecordType::JsonCommand, json_data.as_bytes()).expect("decode failed");
+        assert_eq!(cmd, Command::Rm("key".to_owned()));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let commands = [
+            Command::Set("key".to_owned(), b"\0binary\n\"value\"".to_vec()),
+            Command::Rm("key".to_owned()),
+            Command::SetExpiring("key".to_owned(), vec![], 1_700_000_000_000),
+        ];
+        for (seq, cmd) in commands.into_iter().enumerate() {
+            let payload = cmd.encode(seq as u64).expect("encode failed");
+            assert_eq!(
+                Command::decode_with_seq(RecordType::Command, &payload).expect("decode failed"),
+                (cmd, Some(seq as u64))
+            );
+            assert!(Command::decode(RecordType::Command, &payload[..payload.len() - 1]).is_err());
+            let len = payload.len() as u64;
+            assert!((0..payload.len()).all(|cut| prefix_fits(&payload[..cut], len)));
+            assert!(!prefix_fits(&payload[..payload.len() - 1], len ^ 0xff00));
+        }
+
+        // Records written before sequence numbers were introduced
+        let payload = b"\x02\x03\0\0\0key";
+        assert_eq!(
+            Command::decode_with_seq(RecordType::Command, payload).expect("decode failed"),
+            (Command::Rm("key".to_owned()), None)
+        );
+    }
+}
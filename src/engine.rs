@@ -0,0 +1,25 @@
+use crate::Result;
+
+/// Storage engine behind a key/value store.
+///
+/// Everything above the storage layer, such as the `kvs` binary, goes through this
+/// trait so that engines can be swapped and compared.
+///
+/// Engines take `&self` and are `Send + Sync`, so one engine can serve requests
+/// from many threads at once.
+pub trait KvsEngine: Send + Sync {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key.
+    ///
+    /// Returns an error if the key does not exist.
+    fn remove(&self, key: String) -> Result<()>;
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use failure::Fail;
+
+/// Errors that callers may want to handle, as opposed to report.
+///
+/// They are returned inside the crate's `Error`, recover them with
+/// `Error::downcast_ref::<KvsError>()`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KvsError {
+    /// The key to remove does not exist.
+    KeyNotFound,
+    /// A key read by a transaction was written by someone else before it committed.
+    TransactionConflict,
+    /// The value of a counter is not an integer.
+    NotAnInteger,
+    /// Adding to a counter would take it out of the range of an `i64`.
+    CounterOverflow,
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::TransactionConflict => write!(f, "transaction conflict"),
+            KvsError::NotAnInteger => write!(f, "value is not an integer"),
+            KvsError::CounterOverflow => write!(f, "counter overflow"),
+        }
+    }
+}
+
+impl Fail for KvsError {}
@@ -0,0 +1,152 @@
+//! Hint files.
+//!
+//! When compaction rewrites segment `<generation>.log` it also writes
+//! `<generation>.hint`, which lists for every key the segment holds a record of
+//! where that record is and whether it is a tombstone. Opening the store loads the
+//! hint instead of reading and deserializing every record of the segment.
+//!
+//! A hint file holds a single framed record (see `record`), so damage is caught by
+//! its checksum. The payload starts with the length of the segment it describes,
+//! which tells a stale hint apart, and the highest sequence number the segment held
+//! before compaction dropped any record, or 0 if none. The entries follow:
+//!
+//! ```text
+//! +-----------+---------------+-----+--------------+-----------+
+//! | kind (u8) | key len (u32) | key | offset (u64) | len (u64) |
+//! +-----------+---------------+-----+--------------+-----------+
+//! ```
+//!
+//! Entries for a set that expires are followed by its deadline, in milliseconds
+//! since the Unix epoch (u64).
+
+use failure::format_err;
+
+use crate::{
+    Result,
+    record::{self, RecordType, take},
+};
+
+const KIND_SET: u8 = 1;
+const KIND_TOMBSTONE: u8 = 2;
+const KIND_SET_EXPIRING: u8 = 3;
+
+/// Where the record of one key lives in a segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HintEntry {
+    pub(crate) key: String,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) tombstone: bool,
+    /// Deadline of a set that expires, in milliseconds since the Unix epoch.
+    pub(crate) expires_at: Option<u64>,
+}
+
+/// Builds the content of a hint file describing a segment of `segment_len` bytes
+/// whose highest sequence number is `max_seq`.
+pub(crate) fn encode(segment_len: u64, max_seq: u64, entries: &[HintEntry]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&segment_len.to_le_bytes());
+    payload.extend_from_slice(&max_seq.to_le_bytes());
+    for entry in entries {
+        let key_len: u32 = entry
+            .key
+            .len()
+            .try_into()
+            .map_err(|_| format_err!("key too large: {} bytes", entry.key.len()))?;
+        payload.push(match (entry.tombstone, entry.expires_at) {
+            (true, _) => KIND_TOMBSTONE,
+            (false, None) => KIND_SET,
+            (false, Some(_)) => KIND_SET_EXPIRING,
+        });
+        payload.extend_from_slice(&key_len.to_le_bytes());
+        payload.extend_from_slice(entry.key.as_bytes());
+        payload.extend_from_slice(&entry.offset.to_le_bytes());
+        payload.extend_from_slice(&entry.len.to_le_bytes());
+        if let (false, Some(expires_at)) = (entry.tombstone, entry.expires_at) {
+            payload.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+    record::encode(RecordType::Hint, &payload)
+}
+
+/// Parses the content of a hint file, returns the segment length it was written
+/// for, the highest sequence number of the segment and the entries.
+pub(crate) fn decode(buf: &[u8]) -> Result<(u64, u64, Vec<HintEntry>)> {
+    let (record_type, mut payload) = record::decode(buf)?;
+    match record_type {
+        RecordType::Hint => {}
+        RecordType::UnnumberedHint => {
+            return Err(format_err!("hint file without sequence numbers"));
+        }
+        _ => return Err(format_err!("not a hint file")),
+    }
+
+    let segment_len = u64::from_le_bytes(take(&mut payload)?);
+    let max_seq = u64::from_le_bytes(take(&mut payload)?);
+    let mut entries = Vec::new();
+    while !payload.is_empty() {
+        let [kind] = take(&mut payload)?;
+        let key_len = u32::from_le_bytes(take(&mut payload)?) as usize;
+        if payload.len() < key_len {
+            return Err(format_err!("hint entry truncated"));
+        }
+        let (key, rest) = payload.split_at(key_len);
+        payload = rest;
+        let key = String::from_utf8(key.to_vec())?;
+        let offset = u64::from_le_bytes(take(&mut payload)?);
+        let len = u64::from_le_bytes(take(&mut payload)?);
+        let (tombstone, expires_at) = match kind {
+            KIND_SET => (false, None),
+            KIND_TOMBSTONE => (true, None),
+            KIND_SET_EXPIRING => (false, Some(u64::from_le_bytes(take(&mut payload)?))),
+            _ => return Err(format_err!("unknown hint entry kind {}", kind)),
+        };
+        entries.push(HintEntry {
+            key,
+            offset,
+            len,
+            tombstone,
+            expires_at,
+        });
+    }
+    Ok((segment_len, max_seq, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HintEntry, decode, encode};
+
+    #[test]
+    fn test_encode_decode() {
+        let entries = vec![
+            HintEntry {
+                key: "key1".to_owned(),
+                offset: 0,
+                len: 42,
+                tombstone: false,
+                expires_at: None,
+            },
+            HintEntry {
+                key: "key2".to_owned(),
+                offset: 42,
+                len: 20,
+                tombstone: true,
+                expires_at: None,
+            },
+            HintEntry {
+                key: "key3".to_owned(),
+                offset: 62,
+                len: 30,
+                tombstone: false,
+                expires_at: Some(1_700_000_000_000),
+            },
+        ];
+        let buf = encode(92, 7, &entries).expect("encode failed");
+        let (segment_len, max_seq, decoded) = decode(&buf).expect("decode failed");
+        assert_eq!(segment_len, 92);
+        assert_eq!(max_seq, 7);
+        assert_eq!(decoded, entries);
+
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -1,335 +1,1385 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self, File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    collections::HashMap,
+    fs,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use failure::format_err;
-use serde_derive::{Deserialize, Serialize};
+use im::OrdMap;
+use serde::{Serialize, de::DeserializeOwned};
 
-use crate::Result;
+use crate::{
+    Changes, Durability, Event, KvsEngine, KvsError, Options, Result, Scan, ScanBytes, Snapshot,
+    Stats, Transaction, TypedStore, WriteBatch,
+    command::Command,
+    hint::HintEntry,
+    log::{self, Log, LogPointer, Replayed},
+    record::RecordType,
+    scan::ScanSource,
+    watch::{self, Watcher},
+};
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "cmd", content = "params")]
-enum Command {
-    Set(String, String),
-    Rm(String),
+/// Where the current value of a key is in the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) pointer: LogPointer,
+    /// Stamp of the write that set the value, see `Shared::next_version`.
+    version: u64,
+    /// When the value expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
 }
 
-#[test]
-fn test_serialize() {
-    let set_cmd = Command::Set("key".to_string(), "value".to_string());
-    let rm_cmd = Command::Rm("key".to_string());
-    let json_data = serde_json::to_string(&set_cmd).expect("marshal failed");
-    assert_eq!(json_data, r#"{"cmd":"Set","params":["key","value"]}"#);
-    let json_data = serde_json::to_string(&rm_cmd).expect("marshal failed");
-    assert_eq!(json_data, r#"{"cmd":"Rm","params":"key"}"#);
+impl IndexEntry {
+    /// Whether the value has expired at `now`, as given by `now_millis`.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
-struct LogFile {
-    head_log: File,
-    dir_path: String,
-    wal_path: String,
+/// Ordered map from every key to its `IndexEntry`.
+///
+/// Clones share their nodes until modified, so a snapshot of the index is cheap.
+pub(crate) type Index = OrdMap<String, IndexEntry>;
+
+/// Looks up the entry of `key`, unless its value has expired at `now`.
+pub(crate) fn live_entry(log_pointer_map: &Index, key: &str, now: u64) -> Option<IndexEntry> {
+    log_pointer_map
+        .get(key)
+        .filter(|entry| !entry.is_expired(now))
+        .copied()
 }
 
-impl LogFile {
-    fn new(dir_path: &str) -> Self {
-        let wal_path = format!("{}/head.log", dir_path);
-        let f = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(&wal_path)
-            .expect("cannot open log file");
+/// Finds the first key in the range whose value has not expired at `now`.
+pub(crate) fn first_live_in_range<'a>(
+    log_pointer_map: &'a Index,
+    start: Bound<&String>,
+    end: Bound<&String>,
+    now: u64,
+) -> Option<(&'a String, &'a IndexEntry)> {
+    log_pointer_map
+        .range::<_, String>((start, end))
+        .find(|(_, entry)| !entry.is_expired(now))
+}
+
+/// Current time in milliseconds since the Unix epoch, the unit of expiry deadlines.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
 
-        Self {
-            head_log: f,
-            wal_path,
-            dir_path: dir_path.to_string(),
+/// The `KvStore` stores key/value pairs, with string keys and either string or
+/// binary values.
+///
+/// Values are persisted in a log on disk, and an in-memory index maps every key to
+/// the record holding its value. The index is ordered, so keys can be listed in
+/// order with `scan` and `scan_prefix`.
+///
+/// Cloning a `KvStore` is cheap: all clones share the same log and index and can
+/// be used from different threads. Reads run in parallel, writes are serialized.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("key".to_owned(), "value".to_owned());
+/// let val = store.get("key".to_owned()).unwrap();
+/// assert_eq!(val, Some("value".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct KvStore {
+    shared: Arc<Shared>,
+    compactor: Arc<Compactor>,
+    discarded_bytes: u64,
+}
+
+/// Handle on the compaction worker, which is stopped once the last clone of the
+/// `KvStore` is dropped.
+///
+/// In `Durability::Periodic` mode the worker also syncs the writes left unsynced
+/// for the interval.
+struct Compactor {
+    // Wakes the worker up, dropped to stop it
+    sender: Option<Sender<()>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+/// State shared between a `KvStore` and its compaction worker.
+///
+/// Locks are always taken in field order: `log`, then `log_pointer_map`, then
+/// `watchers`.
+struct Shared {
+    dir_path: PathBuf,
+    options: Options,
+    log: RwLock<Log>,
+    log_pointer_map: RwLock<Index>,
+    // Receivers of the events of the writes, see `KvStore::watch`
+    watchers: Mutex<Vec<Watcher>>,
+    // Version given to the next write, stored in its record as its sequence number.
+    // Unlike a log pointer, which compaction may reuse, it never repeats, so it tells
+    // whether a key changed since it was read.
+    next_version: AtomicU64,
+    // Set while a compaction is queued or running
+    compacting: AtomicBool,
+    // Why the last background compaction or sync failed, reported by the next write
+    background_error: Mutex<Option<String>>,
+    // Namespaces opened so far, by name
+    namespaces: Mutex<HashMap<String, KvStore>>,
+    // Set once the store was dropped as a namespace, see `KvStore::drop_namespace`
+    dropped: AtomicBool,
+}
+
+impl KvStore {
+    /// Creates a `KvStore` with default `Options`.
+    ///
+    /// If the previous process died in the middle of a write, the incomplete record
+    /// at the end of the log is dropped, see `discarded_bytes`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Creates a `KvStore` with the given `Options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        let path: PathBuf = path.into();
+        let mut log = Log::open(&path, &options)?;
+        let mut log_pointer_map = Index::new();
+        let (discarded_bytes, next_version) = replay_log_file(&mut log, &mut log_pointer_map)?;
+
+        let shared = Arc::new(Shared {
+            dir_path: path,
+            options,
+            log: RwLock::new(log),
+            log_pointer_map: RwLock::new(log_pointer_map),
+            watchers: Mutex::new(Vec::new()),
+            next_version: AtomicU64::new(next_version),
+            compacting: AtomicBool::new(false),
+            background_error: Mutex::new(None),
+            namespaces: Mutex::new(HashMap::new()),
+            dropped: AtomicBool::new(false),
+        });
+
+        let (compact_sender, compact_receiver) = mpsc::channel();
+        let worker_shared = Arc::clone(&shared);
+        let compact_worker = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || worker_shared.run_worker(compact_receiver))?;
+
+        Ok(KvStore {
+            shared,
+            compactor: Arc::new(Compactor {
+                sender: Some(compact_sender),
+                worker: Some(compact_worker),
+            }),
+            discarded_bytes,
+        })
+    }
+
+    /// Number of bytes cut off the end of the log by `open` because they held an
+    /// incomplete record from an interrupted write.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    /// Counts the keys and measures the log of the store.
+    pub fn stats(&self) -> Stats {
+        let log = self.shared.log.read().unwrap();
+        let log_pointer_map = self.shared.log_pointer_map.read().unwrap();
+        let now = now_millis();
+        Stats {
+            keys: log_pointer_map
+                .values()
+                .filter(|entry| !entry.is_expired(now))
+                .count(),
+            log_bytes: log.size(),
+            stale_bytes: log.stale_bytes(),
+        }
+    }
+
+    /// Opens the namespace `name`, a keyspace of its own within the directory of
+    /// the store.
+    ///
+    /// A namespace is a `KvStore` stored in a subdirectory, so it has its own keys,
+    /// log and stats, and it can be dropped at once with `drop_namespace`. Opening
+    /// the same namespace again returns a clone of the same store.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let users = store.namespace("users").unwrap();
+    /// users.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// assert_eq!(store.get("key".to_owned()).unwrap(), None);
+    ///
+    /// store.drop_namespace("users").unwrap();
+    /// let users = store.namespace("users").unwrap();
+    /// assert_eq!(users.get("key".to_owned()).unwrap(), None);
+    /// ```
+    pub fn namespace(&self, name: &str) -> Result<KvStore> {
+        let path = self.namespace_path(name)?;
+        let mut namespaces = self.shared.namespaces.lock().unwrap();
+        if let Some(store) = namespaces.get(name) {
+            return Ok(store.clone());
+        }
+        fs::create_dir_all(&path)?;
+        let store = KvStore::open_with_options(path, self.shared.options.clone())?;
+        namespaces.insert(name.to_owned(), store.clone());
+        Ok(store)
+    }
+
+    /// Deletes the namespace `name` along with all its keys, does nothing if it does
+    /// not exist.
+    ///
+    /// Handles on the namespace that are still around see it empty, and fail to
+    /// write.
+    pub fn drop_namespace(&self, name: &str) -> Result<()> {
+        let path = self.namespace_path(name)?;
+        // Held until the files are gone, so the namespace cannot be reopened meanwhile
+        let mut namespaces = self.shared.namespaces.lock().unwrap();
+        if let Some(store) = namespaces.remove(name) {
+            store.shared.close();
+        }
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+            log::sync_dir(path.parent().unwrap())?;
         }
+        Ok(())
     }
 
-    fn append(&mut self, buf: &[u8]) -> Result<usize> {
-        let file_index = self.head_log.seek(std::io::SeekFrom::End(0))?;
-        let mut n = 0;
-        if file_index > 0 {
-            n += self.head_log.write(b"\n")?;
+    /// Writes a copy of the store to `dest_dir`, which must not exist yet, that
+    /// can be opened like any store. Writes can go on meanwhile.
+    ///
+    /// The copy holds the keys that are live when the backup starts, compacted, and
+    /// nothing written afterwards. It is built in a new directory next to `dest_dir`
+    /// and renamed into place once complete, so a crash never leaves a partial copy
+    /// at `dest_dir`. Namespaces are copied as well, each as of the moment its own
+    /// copy starts.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// # let backup_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// store.backup(backup_dir.path().join("backup")).unwrap();
+    ///
+    /// let backup = KvStore::open(backup_dir.path().join("backup")).unwrap();
+    /// assert_eq!(backup.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+    /// ```
+    pub fn backup(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        if dest_dir.exists() {
+            return Err(format_err!("{} already exists", dest_dir.display()));
         }
-        n += self.head_log.write(buf)?;
-        Ok(n)
+        let name = dest_dir
+            .file_name()
+            .ok_or_else(|| format_err!("invalid backup directory {}", dest_dir.display()))?;
+        let parent = dest_dir
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        // Removed if the backup fails, left behind only by a crash
+        let temp_dir = tempfile::Builder::new()
+            .prefix(&format!(".{}.", name.to_string_lossy()))
+            .suffix(".tmp")
+            .tempdir_in(parent)?;
+        self.copy_to(temp_dir.path())?;
+
+        fs::rename(temp_dir.keep(), dest_dir)?;
+        log::sync_dir(parent)
     }
 
-    fn read_until(&mut self, delimiter: char, buf: &mut [u8]) -> Result<usize> {
-        const CHUNK_SIZE: usize = 8;
-        let mut offset = 0;
-        'outer: loop {
-            let n: usize = self.head_log.read(&mut buf[offset..offset + CHUNK_SIZE])?;
-            if n == 0 {
-                break;
+    /// Writes the copy `backup` makes of the store and its namespaces to `dir`.
+    fn copy_to(&self, dir: &Path) -> Result<()> {
+        let (log_pointer_map, segments) = {
+            let log = self.shared.log.read().unwrap();
+            let log_pointer_map = self.shared.log_pointer_map.read().unwrap().clone();
+            (log_pointer_map, log.pin())
+        };
+        // Synced once complete, whatever the durability mode of the store
+        let backup_options = Options {
+            durability: Durability::Never,
+            ..self.shared.options.clone()
+        };
+        let mut backup_log = Log::open(dir, &backup_options)?;
+        let now = now_millis();
+        let mut entries: Vec<IndexEntry> = log_pointer_map
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .copied()
+            .collect();
+        // Records are copied as they are, keeping their deadline and sequence number,
+        // so they must stay in write order for `changes` to read them right
+        entries.sort_unstable_by_key(|entry| entry.version);
+        for entry in entries {
+            let (record_type, payload) = segments.read(entry.pointer)?;
+            backup_log.append(record_type, &payload)?;
+        }
+        backup_log.sync()?;
+        drop(backup_log);
+
+        let namespaces_dir = self.shared.dir_path.join("namespaces");
+        if namespaces_dir.exists() {
+            let backup_namespaces_dir = dir.join("namespaces");
+            fs::create_dir(&backup_namespaces_dir)?;
+            for dir_entry in fs::read_dir(&namespaces_dir)? {
+                let name = dir_entry?.file_name();
+                let name = name
+                    .to_str()
+                    .ok_or_else(|| format_err!("invalid namespace name {:?}", name))?;
+                let backup_dir = backup_namespaces_dir.join(name);
+                fs::create_dir(&backup_dir)?;
+                self.namespace(name)?.copy_to(&backup_dir)?;
             }
-            for (index, &current_char) in buf.iter().enumerate().skip(offset).take(n) {
-                if current_char == delimiter as u8 {
-                    // Rewind to index delimiter + 1
-                    self.head_log
-                        .seek_relative((index - offset + 1) as i64 - n as i64)?;
-                    offset = index + 1;
-                    break 'outer;
+            log::sync_dir(&backup_namespaces_dir)?;
+            log::sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Directory of the namespace `name`.
+    fn namespace_path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(format_err!("invalid namespace name {:?}", name));
+        }
+        Ok(self.shared.dir_path.join("namespaces").join(name))
+    }
+
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// Values are stored as they are, `set` is the same as `set_bytes` with the
+    /// UTF-8 bytes of the string.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes("key".to_owned(), vec![0, 159, 146, 150]).unwrap();
+    /// assert_eq!(
+    ///     store.get_bytes("key".to_owned()).unwrap(),
+    ///     Some(vec![0, 159, 146, 150])
+    /// );
+    /// assert!(store.get("key".to_owned()).is_err());
+    /// ```
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.write_commands(|_, _| Ok(vec![Command::Set(key, value)]))
+    }
+
+    /// Gets the bytes value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(&key)?.map(|(value, _)| value))
+    }
+
+    /// Sets the value of a string key to a string, like `set`, for `ttl` only.
+    ///
+    /// Once `ttl` has elapsed the key reads as absent, from this process or any
+    /// that reopens the store, and compaction drops it from the log.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store
+    ///     .set_with_ttl("session".to_owned(), "token".to_owned(), Duration::from_secs(60))
+    ///     .unwrap();
+    /// assert_eq!(store.get("session".to_owned()).unwrap(), Some("token".to_owned()));
+    /// ```
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_commands(|_, _| {
+            Ok(vec![Command::SetExpiring(
+                key,
+                value.into_bytes(),
+                expires_at,
+            )])
+        })
+    }
+
+    /// Applies all the sets and removes of `batch` atomically: after a crash, either
+    /// all or none of them are found in the store.
+    ///
+    /// Like `remove`, fails if the batch removes a key that does not exist at that
+    /// point of the batch, in which case nothing is applied.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_commands(|_, log_pointer_map| {
+            let now = now_millis();
+            // Whether each key touched so far exists at this point of the batch
+            let mut exists: HashMap<&str, bool> = HashMap::new();
+            for cmd in &batch.commands {
+                match cmd {
+                    Command::Set(key, _) | Command::SetExpiring(key, _, _) => {
+                        exists.insert(key, true);
+                    }
+                    Command::Rm(key) => {
+                        let found = exists
+                            .get(key.as_str())
+                            .copied()
+                            .unwrap_or_else(|| live_entry(log_pointer_map, key, now).is_some());
+                        if !found {
+                            return Err(KvsError::KeyNotFound.into());
+                        }
+                        exists.insert(key, false);
+                    }
                 }
             }
-            offset += n;
-        }
+            Ok(batch.commands)
+        })
+    }
+
+    /// Replaces the value of `key` with `new` if its current value is `expected`,
+    /// `None` standing for an absent key on both sides. Returns whether it did.
+    ///
+    /// The comparison and the write happen atomically with respect to every other
+    /// write to the store.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_owned(), "1".to_owned()).unwrap();
+    /// let swapped = store
+    ///     .compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned()))
+    ///     .unwrap();
+    /// assert!(swapped);
+    /// let swapped = store
+    ///     .compare_and_swap("key".to_owned(), Some("1".to_owned()), None)
+    ///     .unwrap();
+    /// assert!(!swapped);
+    /// assert_eq!(store.get("key".to_owned()).unwrap(), Some("2".to_owned()));
+    /// ```
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.write_if(
+            key,
+            |current| current == expected.as_ref().map(String::as_bytes),
+            new,
+        )
+    }
+
+    /// Sets `key` to `value` unless it already exists, returns whether it did.
+    pub fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.write_if(key, |current| current.is_none(), Some(value))
+    }
+
+    /// Sets `key` to `value` only if it already exists, returns whether it did.
+    pub fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.write_if(key, |current| current.is_some(), Some(value))
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the new value, an
+    /// absent key counting as 0.
+    ///
+    /// Like `compare_and_swap`, this is atomic with respect to other writes. A key
+    /// set with `set_with_ttl` keeps its deadline. Fails with
+    /// `KvsError::NotAnInteger` if the value does not parse as an `i64`, and with
+    /// `KvsError::CounterOverflow` if the result does not fit in one.
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// assert_eq!(store.incr("hits".to_owned(), 5).unwrap(), 5);
+    /// assert_eq!(store.decr("hits".to_owned(), 2).unwrap(), 3);
+    /// ```
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.update_counter(key, |count| count.checked_add(delta))
+    }
+
+    /// Subtracts `delta` from the integer stored at `key`, like `incr`.
+    pub fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.update_counter(key, |count| count.checked_sub(delta))
+    }
+
+    /// Returns a view of the namespace `name` holding values of type `T`, see
+    /// `TypedStore`. Fails if `name` is not a valid namespace name.
+    pub fn typed<T: Serialize + DeserializeOwned>(&self, name: &str) -> Result<TypedStore<T>> {
+        Ok(TypedStore::new(self.namespace(name)?, name))
+    }
+
+    /// Starts an optimistic transaction, see `Transaction`.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Iterates in key order over the key/value pairs whose key is in `range`.
+    ///
+    /// The iterator does not hold any lock: each step looks up the key following
+    /// the previous one, so writes that land meanwhile may or may not be seen.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// for key in ["a", "b", "c", "d"] {
+    ///     store.set(key.to_owned(), key.to_uppercase()).unwrap();
+    /// }
+    /// let pairs: Vec<(String, String)> = store
+    ///     .scan("b".to_owned().."d".to_owned())
+    ///     .collect::<kvs::Result<_>>()
+    ///     .unwrap();
+    /// assert_eq!(pairs, [("b".to_owned(), "B".to_owned()), ("c".to_owned(), "C".to_owned())]);
+    /// ```
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Scan<'_> {
+        Scan::new(self.scan_bytes(range))
+    }
+
+    /// Iterates in key order over the key/value pairs whose key starts with
+    /// `prefix`, like `scan`.
+    pub fn scan_prefix(&self, prefix: impl Into<String>) -> Scan<'_> {
+        Scan::new(self.scan_prefix_bytes(prefix))
+    }
+
+    /// Iterates over the key/value pairs whose key is in `range`, like `scan`, with
+    /// bytes values.
+    pub fn scan_bytes(&self, range: impl RangeBounds<String>) -> ScanBytes<'_> {
+        ScanBytes::range(self, range)
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix`, like
+    /// `scan_prefix`, with bytes values.
+    pub fn scan_prefix_bytes(&self, prefix: impl Into<String>) -> ScanBytes<'_> {
+        ScanBytes::prefix(self, prefix.into())
+    }
+
+    /// Returns a receiver of the changes to `key`.
+    ///
+    /// An event is sent every time a write to the key commits, in commit order.
+    /// Keys that expire do not produce an event. Dropping the receiver stops the
+    /// watch.
+    ///
+    /// ```rust
+    /// # use kvs::{Event, KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// let events = store.watch("key".to_owned());
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// store.remove("key".to_owned()).unwrap();
+    /// assert_eq!(
+    ///     events.try_iter().collect::<Vec<_>>(),
+    ///     [
+    ///         Event::Set("key".to_owned(), b"value".to_vec()),
+    ///         Event::Removed("key".to_owned()),
+    ///     ]
+    /// );
+    /// ```
+    pub fn watch(&self, key: String) -> Receiver<Event> {
+        self.add_watcher(key, true)
+    }
+
+    /// Returns a receiver of the changes to the keys that start with `prefix`,
+    /// like `watch`.
+    pub fn watch_prefix(&self, prefix: impl Into<String>) -> Receiver<Event> {
+        self.add_watcher(prefix.into(), false)
+    }
+
+    fn add_watcher(&self, prefix: String, exact: bool) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.watchers.lock().unwrap().push(Watcher {
+            prefix,
+            exact,
+            sender,
+        });
+        receiver
+    }
+
+    /// Reads the writes committed to the store in order, starting with the one
+    /// numbered `cursor`, see `Changes`.
+    ///
+    /// Pass 0 to read from the start of the log, or the `cursor` of a previous
+    /// reader to resume where it stopped.
+    pub fn changes(&self, cursor: u64) -> Changes {
+        Changes::new(self.clone(), cursor)
+    }
 
-        Ok(offset)
+    /// Takes a read-only view of the store as it is now.
+    ///
+    /// Reads on the snapshot keep returning the values of that moment, whatever is
+    /// written or compacted afterwards, and never block writers. Taking one is
+    /// cheap: the index is shared with the store until either changes. The segment
+    /// files it reads from are kept open though, so the disk space compaction
+    /// frees is only given back once the snapshot is dropped.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # let temp_dir = tempfile::TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_owned(), "old".to_owned()).unwrap();
+    /// let snapshot = store.snapshot();
+    /// store.set("key".to_owned(), "new".to_owned()).unwrap();
+    /// assert_eq!(snapshot.get("key".to_owned()).unwrap(), Some("old".to_owned()));
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        let log = self.shared.log.read().unwrap();
+        let log_pointer_map = self.shared.log_pointer_map.read().unwrap().clone();
+        Snapshot::new(log_pointer_map, log.pin(), now_millis())
+    }
+
+    /// Directory holding the log.
+    pub(crate) fn dir_path(&self) -> &Path {
+        &self.shared.dir_path
     }
 
-    fn current_file_offset(&mut self) -> Result<u64> {
-        let offset = self.head_log.stream_position()?;
-        Ok(offset)
+    /// Generations of the log segments, oldest first.
+    pub(crate) fn generations(&self) -> Vec<u64> {
+        self.shared.log.read().unwrap().generations()
     }
 
-    fn read_until_from_offset(
-        &mut self,
-        delimiter: char,
-        offset: u64,
-        buf: &mut [u8],
-    ) -> Result<usize> {
-        self.head_log.seek(SeekFrom::Start(offset))?;
-        let n = self.read_until(delimiter, buf)?;
-        Ok(n)
+    /// Gets the value of a key along with its version, which changes whenever the
+    /// key is written.
+    pub(crate) fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+        let log = self.shared.log.read().unwrap();
+        let log_pointer_map = self.shared.log_pointer_map.read().unwrap();
+        let Some(entry) = live_entry(&log_pointer_map, key, now_millis()) else {
+            return Ok(None);
+        };
+        Ok(Some((read_value(log.read(entry.pointer)?)?, entry.version)))
     }
 
-    // drop this log file, return new log file
-    fn compact(
-        &mut self,
-        retained_offsets: &[u64],
-        mut on_write_fn: impl FnMut(&[u8], u64),
+    /// Commits the writes of a transaction if none of the keys it read changed
+    /// since, `reads` holding the version each had, if it existed.
+    pub(crate) fn commit(
+        &self,
+        reads: HashMap<String, Option<u64>>,
+        writes: HashMap<String, Option<String>>,
     ) -> Result<()> {
-        // Read file from start
-        self.head_log.flush()?;
-        self.head_log.rewind()?;
+        self.write_commands(|_, log_pointer_map| {
+            let now = now_millis();
+            for (key, version) in &reads {
+                if live_entry(log_pointer_map, key, now).map(|entry| entry.version) != *version {
+                    return Err(KvsError::TransactionConflict.into());
+                }
+            }
+            Ok(writes
+                .into_iter()
+                .filter_map(|(key, value)| match value {
+                    Some(value) => Some(Command::Set(key, value.into_bytes())),
+                    // Removing a key the transaction created itself leaves nothing
+                    None => live_entry(log_pointer_map, &key, now)
+                        .is_some()
+                        .then_some(Command::Rm(key)),
+                })
+                .collect())
+        })
+    }
 
-        let temp_path = format!("{}/head.log.compact", self.dir_path);
+    /// Sets `key` to `new`, or removes it if `None`, provided `condition` holds for
+    /// its current value. Returns whether the condition held.
+    fn write_if(
+        &self,
+        key: String,
+        condition: impl FnOnce(Option<&[u8]>) -> bool,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut written = false;
+        self.write_commands(|log, log_pointer_map| {
+            let current = match live_entry(log_pointer_map, &key, now_millis()) {
+                Some(entry) => Some(read_value(log.read(entry.pointer)?)?),
+                None => None,
+            };
+            if !condition(current.as_deref()) {
+                return Ok(vec![]);
+            }
+            written = true;
+            Ok(match new {
+                Some(value) => vec![Command::Set(key, value.into_bytes())],
+                // Removing an absent key leaves nothing to write
+                None if current.is_some() => vec![Command::Rm(key)],
+                None => vec![],
+            })
+        })?;
+        Ok(written)
+    }
 
-        let mut new_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(&temp_path)
-            .expect("cannot open log file");
-
-        // Write to new log file
-        for &offset in retained_offsets {
-            let mut buf = [0; 1000];
-            let mut n = self.read_until_from_offset('\n', offset, &mut buf)?;
-            let mut cur_offset = new_file.seek(SeekFrom::Current(0))?;
-            if cur_offset > 0 {
-                new_file.write(b"\n")?;
-                cur_offset += 1;
+    /// Replaces the integer stored at `key` with what `update` makes of it, `None`
+    /// meaning it overflowed.
+    fn update_counter(&self, key: String, update: impl FnOnce(i64) -> Option<i64>) -> Result<i64> {
+        let mut count = 0;
+        self.write_commands(|log, log_pointer_map| {
+            let entry = live_entry(log_pointer_map, &key, now_millis());
+            if let Some(entry) = entry {
+                let value = read_value(log.read(entry.pointer)?)?;
+                count = std::str::from_utf8(&value)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or(KvsError::NotAnInteger)?;
             }
-            on_write_fn(&buf[0..n], cur_offset);
-            if buf[n - 1] == b'\n' {
-                n -= 1;
+            count = update(count).ok_or(KvsError::CounterOverflow)?;
+            let value = count.to_string().into_bytes();
+            Ok(vec![match entry.and_then(|entry| entry.expires_at) {
+                Some(expires_at) => Command::SetExpiring(key, value, expires_at),
+                None => Command::Set(key, value),
+            }])
+        })?;
+        Ok(count)
+    }
+
+    /// Appends the commands returned by `build` to the log as one atomic unit and
+    /// applies them to the index.
+    ///
+    /// `build` runs under the write locks, so the log and index it is given are the
+    /// ones the commands are applied to. It can reject the write by failing.
+    fn write_commands(
+        &self,
+        build: impl FnOnce(&Log, &Index) -> Result<Vec<Command>>,
+    ) -> Result<()> {
+        self.check_background_error()?;
+        let needs_compaction = {
+            let mut log = self.shared.log.write().unwrap();
+            let mut log_pointer_map = self.shared.log_pointer_map.write().unwrap();
+            if self.shared.dropped.load(Ordering::SeqCst) {
+                return Err(format_err!("namespace was dropped"));
             }
-            new_file.write(&buf[0..n])?;
-        }
+            let commands = build(&log, &log_pointer_map)?;
+            let first_version = self
+                .shared
+                .next_version
+                .fetch_add(commands.len() as u64, Ordering::SeqCst);
+            let payloads = commands
+                .iter()
+                .zip(first_version..)
+                .map(|(cmd, version)| cmd.encode(version))
+                .collect::<Result<Vec<_>>>()?;
+            let pointers = match payloads.as_slice() {
+                [] => return Ok(()),
+                [payload] => vec![log.append(RecordType::Command, payload)?],
+                _ => log.append_batch(RecordType::Command, &payloads)?,
+            };
 
-        // replace original WAL
-        fs::rename(temp_path, &self.wal_path)?;
-        self.head_log = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(&self.wal_path)
-            .expect("cannot open log file");
+            let mut watchers = self.shared.watchers.lock().unwrap();
+            for ((cmd, pointer), version) in commands.into_iter().zip(pointers).zip(first_version..)
+            {
+                watch::notify(&mut watchers, &cmd);
+                apply_command(&mut log, &mut log_pointer_map, cmd, pointer, version);
+            }
+            self.shared.needs_compaction(&log)
+        };
+
+        if needs_compaction {
+            self.start_compaction();
+        }
 
         Ok(())
     }
+
+    /// Hands compaction over to the worker, unless it is already busy with it.
+    fn start_compaction(&self) {
+        if self.shared.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(sender) = &self.compactor.sender {
+            // The worker only goes away when the store is dropped
+            let _ = sender.send(());
+        }
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        match self.shared.background_error.lock().unwrap().take() {
+            Some(err) => Err(format_err!("{}", err)),
+            None => Ok(()),
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{Command, LogFile};
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value.into_bytes())
+    }
 
-    #[test]
-    fn test_read_until() {
-        let path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), "/tests/data");
-        let mut log_file = LogFile::new(&path);
-        let mut buf = [0; 1000];
-        let n = log_file
-            .read_until('\n', &mut buf)
-            .expect("read until failed");
-        assert_eq!(n, 39);
-        assert_eq!(
-            "{\"cmd\":\"Set\",\"params\":[\"key\",\"value\"]}\n",
-            str::from_utf8(&buf[0..n]).expect("convert string failed")
-        );
-
-        let n = log_file
-            .read_until('\n', &mut buf)
-            .expect("read until failed");
-        assert_eq!(n, 41);
-        assert_eq!(
-            "{\"cmd\":\"Set\",\"params\":[\"key2\",\"value2\"]}\n",
-            str::from_utf8(&buf[0..n]).expect("convert string failed")
-        );
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.write_commands(|_, log_pointer_map| {
+            if live_entry(log_pointer_map, &key, now_millis()).is_none() {
+                return Err(KvsError::KeyNotFound.into());
+            }
+            Ok(vec![Command::Rm(key)])
+        })
     }
 }
 
-impl Drop for LogFile {
+impl ScanSource for KvStore {
+    fn first_in_range(
+        &self,
+        start: Bound<&String>,
+        end: Bound<&String>,
+    ) -> Result<Option<(String, Vec<u8>)>> {
+        let log = self.shared.log.read().unwrap();
+        let log_pointer_map = self.shared.log_pointer_map.read().unwrap();
+        let Some((key, entry)) = first_live_in_range(&log_pointer_map, start, end, now_millis())
+        else {
+            return Ok(None);
+        };
+        Ok(Some((key.clone(), read_value(log.read(entry.pointer)?)?)))
+    }
+}
+
+impl Drop for Compactor {
     fn drop(&mut self) {
-        self.head_log.flush().expect("flush WAL error");
+        // Let a running compaction finish before the log is closed
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            worker.join().expect("compaction worker panicked");
+        }
     }
 }
 
-/// The `KvStore` stores string key/value pairs.
-///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
-///
-/// Example:
-///
-/// ```rust
-/// # use kvs::KvStore;
-/// let mut store = KvStore::open(".").unwrap();
-/// store.set("key".to_owned(), "value".to_owned());
-/// let val = store.get("key".to_owned()).unwrap();
-/// assert_eq!(val, Some("value".to_owned()));
-/// ```
-pub struct KvStore {
-    log_file: LogFile,
-    log_pointer_map: HashMap<String, u64>,
+/// Decodes the value stored by a `Set` record, as returned by `Log::read`.
+pub(crate) fn read_value((record_type, payload): (RecordType, Vec<u8>)) -> Result<Vec<u8>> {
+    match Command::decode(record_type, &payload)? {
+        Command::Set(_, value) | Command::SetExpiring(_, value, _) => Ok(value),
+        _ => panic!("invalid write a head log offset"),
+    }
 }
 
-impl KvStore {
-    /// Creates a `KvStore`.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path: PathBuf = path.into();
-        let Some(path) = path.as_path().to_str() else {
-            return Err(format_err!("cannot convert path"));
-        };
-        let log_file = LogFile::new(path);
-        let log_pointer_map = HashMap::new();
-        let mut obj = KvStore {
-            log_file,
-            log_pointer_map,
+/// Points the index at the record of `cmd` just appended at `pointer`, and marks
+/// the records it supersedes as stale.
+fn apply_command(
+    log: &mut Log,
+    log_pointer_map: &mut Index,
+    cmd: Command,
+    pointer: LogPointer,
+    version: u64,
+) {
+    let old_entry = match cmd {
+        Command::Set(key, _) => log_pointer_map.insert(
+            key,
+            IndexEntry {
+                pointer,
+                version,
+                expires_at: None,
+            },
+        ),
+        Command::SetExpiring(key, _, expires_at) => log_pointer_map.insert(
+            key,
+            IndexEntry {
+                pointer,
+                version,
+                expires_at: Some(expires_at),
+            },
+        ),
+        Command::Rm(key) => {
+            // The tombstone itself is only needed until older segments are compacted
+            log.add_stale(pointer.generation, pointer.len);
+            log_pointer_map.remove(&key)
+        }
+    };
+    if let Some(IndexEntry { pointer, .. }) = old_entry {
+        log.add_stale(pointer.generation, pointer.len);
+    }
+}
+
+/// Rebuilds the index from the log, returns the number of bytes discarded from a
+/// torn tail and the version to give to the next write.
+///
+/// Sets that have expired by now are replayed like removes.
+fn replay_log_file(log: &mut Log, log_pointer_map: &mut Index) -> Result<(u64, u64)> {
+    let now = now_millis();
+    let mut version = 0;
+    // Compaction may have dropped the records of the newest writes, which hints
+    // keep track of
+    let mut next_seq = 0;
+    let mut stale_bytes: HashMap<u64, u64> = HashMap::new();
+    let mut mark_stale = |pointer: LogPointer| {
+        *stale_bytes.entry(pointer.generation).or_default() += pointer.len;
+    };
+
+    let discarded_bytes = log.replay(|replayed| {
+        let (key, pointer, expires_at) = match replayed {
+            Replayed::Record(
+                pointer,
+                record_type @ (RecordType::Command | RecordType::JsonCommand),
+                payload,
+            ) => {
+                let (cmd, seq) = Command::decode_with_seq(record_type, payload)?;
+                if let Some(seq) = seq {
+                    next_seq = next_seq.max(seq + 1);
+                }
+                match cmd {
+                    Command::Set(k, _) => (k, Some(pointer), None),
+                    Command::SetExpiring(k, _, expires_at) if expires_at > now => {
+                        (k, Some(pointer), Some(expires_at))
+                    }
+                    Command::SetExpiring(k, _, _) | Command::Rm(k) => {
+                        mark_stale(pointer);
+                        (k, None, None)
+                    }
+                }
+            }
+            Replayed::Record(pointer, record_type, _) => {
+                return Err(format_err!(
+                    "unexpected {:?} record in segment {}",
+                    record_type,
+                    pointer.generation
+                ));
+            }
+            Replayed::HintMaxSeq(max_seq) => {
+                next_seq = next_seq.max(max_seq + 1);
+                return Ok(());
+            }
+            Replayed::Hint(pointer, entry) => match entry.expires_at {
+                Some(expires_at) if expires_at <= now => {
+                    mark_stale(pointer);
+                    (entry.key, None, None)
+                }
+                expires_at => (entry.key, (!entry.tombstone).then_some(pointer), expires_at),
+            },
         };
 
-        let _ = obj.replay_log_file()?;
+        version += 1;
+        let old_entry = match pointer {
+            Some(pointer) => log_pointer_map.insert(
+                key,
+                IndexEntry {
+                    pointer,
+                    version,
+                    expires_at,
+                },
+            ),
+            // The matching set may already have been compacted away
+            None => log_pointer_map.remove(&key),
+        };
+        if let Some(IndexEntry { pointer, .. }) = old_entry {
+            mark_stale(pointer);
+        }
+        Ok(())
+    })?;
 
-        Ok(obj)
+    for (generation, bytes) in stale_bytes {
+        log.add_stale(generation, bytes);
     }
+    Ok((discarded_bytes, next_seq.max(version + 1)))
+}
 
-    fn replay_log_file(&mut self) -> Result<()> {
-        let mut buf = [0; 1000];
+impl Shared {
+    /// Runs a compaction every time one is requested through `receiver`, until it
+    /// is dropped. In `Durability::Periodic` mode, also syncs the log whenever no
+    /// request came for the interval.
+    fn run_worker(&self, receiver: Receiver<()>) {
         loop {
-            let n = self.log_file.read_until('\n', &mut buf)?;
-            if n == 0 {
-                break;
-            }
-
-            let cmd: Command = serde_json::from_slice(&buf[0..n])?;
-            match cmd {
-                Command::Set(k, _) => {
-                    let log_offset = self.log_file.current_file_offset()? - n as u64;
-                    self.log_pointer_map
-                        .entry(k)
-                        .and_modify(|e| *e = log_offset)
-                        .or_insert(log_offset);
-                }
-
-                Command::Rm(k) => {
-                    self.log_pointer_map
-                        .remove(&k)
-                        .expect("WAL log invalid, remove key non existed");
+            let request = match self.options.durability {
+                Durability::Periodic(interval) => receiver.recv_timeout(interval),
+                _ => receiver.recv().map_err(RecvTimeoutError::from),
+            };
+            let result = match request {
+                Ok(()) => {
+                    let result = if self.dropped.load(Ordering::SeqCst) {
+                        Ok(())
+                    } else {
+                        self.log_compact()
+                            .map_err(|err| format_err!("background compaction failed: {}", err))
+                    };
+                    self.compacting.store(false, Ordering::SeqCst);
+                    result
                 }
+                Err(RecvTimeoutError::Timeout) => self
+                    .sync_log()
+                    .map_err(|err| format_err!("background sync failed: {}", err)),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(err) = result {
+                *self.background_error.lock().unwrap() = Some(err.to_string());
             }
         }
+    }
+
+    /// Syncs the writes not synced yet, if any.
+    fn sync_log(&self) -> Result<()> {
+        // Checked under the read lock first, which does not hold up readers
+        if self.dropped.load(Ordering::SeqCst) || self.log.read().unwrap().is_synced() {
+            return Ok(());
+        }
+        let mut log = self.log.write().unwrap();
+        if !log.is_synced() {
+            log.sync()?;
+        }
         Ok(())
     }
 
-    /// Sets the value of a string key to a string.
+    /// Empties the store and stops it for good before its files are deleted,
+    /// along with the namespaces it holds.
+    fn close(&self) {
+        {
+            let _log = self.log.write().unwrap();
+            let mut log_pointer_map = self.log_pointer_map.write().unwrap();
+            self.dropped.store(true, Ordering::SeqCst);
+            *log_pointer_map = Index::new();
+            // Lets watchers know no event will come anymore
+            self.watchers.lock().unwrap().clear();
+        }
+        // Taking the compaction flag for good keeps the worker from starting again,
+        // once it is done with a compaction that may be running
+        while self.compacting.swap(true, Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for (_, store) in self.namespaces.lock().unwrap().drain() {
+            store.shared.close();
+        }
+    }
+
+    /// Whether enough of the log is stale to be worth compacting, according to the
+    /// thresholds in `Options`.
+    fn needs_compaction(&self, log: &Log) -> bool {
+        let stale_bytes = log.stale_bytes();
+        stale_bytes >= self.options.compaction_min_stale_bytes
+            && stale_bytes as f64 >= self.options.compaction_stale_ratio * log.size() as f64
+    }
+
+    /// Rewrites the immutable segments that are stale enough without their stale
+    /// records.
     ///
-    /// If the key already exists, the previous value will be overwritten.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set(key.clone(), value);
+    /// Segments are rewritten without holding any lock, writers keep appending to
+    /// the active segment meanwhile. Each rewritten segment is then swapped in while
+    /// holding the locks, moving the index entries that still point into it.
+    fn log_compact(&self) -> Result<()> {
+        let (generations, all_generations) = {
+            let log = self.log.read().unwrap();
+            (
+                log.stale_generations(self.options.compaction_stale_ratio),
+                log.generations(),
+            )
+        };
+
+        let now = now_millis();
+        for (index, &generation) in generations.iter().enumerate() {
+            // Segments are compacted oldest first. When every segment older than this
+            // one is rewritten in this pass, they have all dropped their stale sets
+            // by now, so tombstones have nothing left to shadow and can go as well.
+            let drop_tombstones = generations[..=index] == all_generations[..=index];
 
-        let serde_bytes = serde_json::to_vec(&cmd)?;
-        self.log_file.append(&serde_bytes)?;
-        let cur_offset = self.log_file.current_file_offset()?;
-        let log_offset = cur_offset - serde_bytes.len() as u64;
+            // Expired values dropped from the segment, with their old location
+            let mut expired = Vec::new();
+            // Kept in the hint, as the record holding it may be dropped, including
+            // by an earlier compaction
+            let mut max_seq = self.log.read().unwrap().hint_max_seq(generation);
+            let compacted = log::rewrite_segment(
+                &self.dir_path,
+                generation,
+                |pointer, record_type, payload| {
+                    let (cmd, seq) = Command::decode_with_seq(record_type, payload)?;
+                    max_seq = max_seq.max(seq.unwrap_or(0));
+                    let log_pointer_map = self.log_pointer_map.read().unwrap();
+                    let current = log_pointer_map.get(cmd.key()).map(|entry| entry.pointer);
+                    match cmd {
+                        // An expired value hides older ones like a tombstone does, so it
+                        // is kept as long as tombstones are, unless something newer
+                        // replaced it
+                        Command::SetExpiring(key, _, expires_at)
+                            if expires_at <= now
+                                && current.is_none_or(|current| current == pointer) =>
+                        {
+                            if drop_tombstones {
+                                expired.push((key, pointer));
+                                Ok(None)
+                            } else {
+                                Ok(Some((key, false, Some(expires_at))))
+                            }
+                        }
+                        // A record that is stale now stays stale, so a racing write can
+                        // only make us keep a record that is no longer needed
+                        Command::Set(key, _) if current == Some(pointer) => {
+                            Ok(Some((key, false, None)))
+                        }
+                        Command::SetExpiring(key, _, expires_at) if current == Some(pointer) => {
+                            Ok(Some((key, false, Some(expires_at))))
+                        }
+                        Command::Rm(key) if !drop_tombstones && current.is_none() => {
+                            Ok(Some((key, true, None)))
+                        }
+                        _ => Ok(None),
+                    }
+                },
+            )?;
 
-        // Update in-mem map log pointer
-        self.log_pointer_map
-            .entry(key)
-            .and_modify(|e| *e = log_offset)
-            .or_insert(log_offset);
+            {
+                let mut log = self.log.write().unwrap();
+                let mut log_pointer_map = self.log_pointer_map.write().unwrap();
+                let mut stale = 0;
+                for (key, old_pointer) in &expired {
+                    if log_pointer_map.get(key).map(|entry| entry.pointer) == Some(*old_pointer) {
+                        log_pointer_map.remove(key);
+                    }
+                }
+                for ((key, tombstone, _), old_pointer, new_pointer) in &compacted.kept {
+                    if *tombstone {
+                        continue;
+                    }
+                    match log_pointer_map.get_mut(key) {
+                        // Moving a value does not change its version
+                        Some(entry) if entry.pointer == *old_pointer => {
+                            entry.pointer = *new_pointer;
+                        }
+                        _ => stale += new_pointer.len,
+                    }
+                }
+                log.install_segment(&compacted, stale)?;
+            }
 
-        // Do log compact
-        let _ = self.log_compact()?;
+            if !compacted.kept.is_empty() {
+                let hint_entries: Vec<HintEntry> = compacted
+                    .kept
+                    .iter()
+                    .map(|((key, tombstone, expires_at), _, pointer)| HintEntry {
+                        key: key.clone(),
+                        offset: pointer.offset,
+                        len: pointer.len,
+                        tombstone: *tombstone,
+                        expires_at: *expires_at,
+                    })
+                    .collect();
+                log::write_hint(
+                    &self.dir_path,
+                    generation,
+                    compacted.len,
+                    max_seq,
+                    &hint_entries,
+                )?;
+            }
+        }
 
         Ok(())
     }
+}
 
-    /// Gets the string value of a given string key.
-    ///
-    /// Returns `None` if the given key does not exist.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let Some(&offset) = self.log_pointer_map.get(&key) else {
-            return Ok(None);
-        };
-        let mut buf = [0; 1000];
-        let n = self
-            .log_file
-            .read_until_from_offset('\n', offset, &mut buf)?;
-        let cmd: Command = serde_json::from_slice(&buf[0..n])?;
-        match cmd {
-            Command::Set(_, value) => Ok(Some(value)),
-            _ => panic!("invalid write a head log offset"),
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::atomic::Ordering, thread, time::Duration};
+
+    use super::KvStore;
+    use crate::{Durability, KvsEngine, Options};
+
+    #[test]
+    fn test_open_json_head_log() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let head_path = dir.path().join("head.log");
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/head.log");
+        fs::copy(&fixture, &head_path).expect("copy failed");
+
+        for _ in 0..2 {
+            let store = KvStore::open(dir.path()).expect("open failed");
+            assert_eq!(store.discarded_bytes(), 0);
+            assert_eq!(store.get("key".to_owned()).expect("get failed"), None);
+            assert_eq!(
+                store.get("key2".to_owned()).expect("get failed"),
+                Some("value2".to_owned())
+            );
+            assert_eq!(
+                store.get("key3".to_owned()).expect("get failed"),
+                Some("value4".to_owned())
+            );
         }
+
+        // A damaged line is not taken for a torn write and cut off
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let head_path = dir.path().join("head.log");
+        let mut content = fs::read(&fixture).expect("read failed");
+        content.truncate(content.len() - 5);
+        fs::write(&head_path, &content).expect("write failed");
+        assert!(KvStore::open(dir.path()).is_err());
+        assert_eq!(fs::read(&head_path).expect("read failed"), content);
     }
 
-    /// Remove a given key.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let offset = self.log_pointer_map.remove(&key);
-        if offset.is_none() {
-            return Err(format_err!("Key not found"));
+    #[test]
+    fn test_compaction_keeps_live_values() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let options = Options {
+            segment_size: 256,
+            ..Options::default()
+        };
+        let mut store =
+            KvStore::open_with_options(dir.path(), options.clone()).expect("open failed");
+        for round in 0..3 {
+            for key_id in 0..20 {
+                store
+                    .set(
+                        format!("key{}", key_id),
+                        format!("value{}-{}", key_id, round),
+                    )
+                    .expect("set failed");
+            }
         }
+        for key_id in 0..10 {
+            store
+                .remove(format!("key{}", key_id))
+                .expect("remove failed");
+        }
+        let size_before = store.shared.log.read().unwrap().size();
+
+        store.shared.log_compact().expect("compaction failed");
+        assert!(store.shared.log.read().unwrap().size() < size_before);
 
-        // Found key, insert to log
-        let cmd = Command::Rm(key);
-        let serde_data = serde_json::to_vec(&cmd)?;
-        self.log_file.append(&serde_data)?;
+        // Writes after a compaction land on top of the compacted segments
+        store
+            .set("key19".to_owned(), "latest".to_owned())
+            .expect("set failed");
+        for _ in 0..2 {
+            for key_id in 0..19 {
+                let expected = (key_id >= 10).then(|| format!("value{}-2", key_id));
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).expect("get failed"),
+                    expected
+                );
+            }
+            assert_eq!(
+                store.get("key19".to_owned()).expect("get failed"),
+                Some("latest".to_owned())
+            );
+            drop(store);
+            store = KvStore::open_with_options(dir.path(), options.clone()).expect("open failed");
+        }
+    }
 
-        // Do log compact
-        let _ = self.log_compact()?;
+    #[test]
+    fn test_periodic_sync_after_writes_stop() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let options = Options {
+            durability: Durability::Periodic(Duration::from_millis(200)),
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(dir.path(), options).expect("open failed");
+        store
+            .set("key".to_owned(), "value".to_owned())
+            .expect("set failed");
+        assert!(!store.shared.log.read().unwrap().is_synced());
 
-        Ok(())
+        thread::sleep(Duration::from_millis(600));
+        assert!(store.shared.log.read().unwrap().is_synced());
     }
 
-    fn log_compact(&mut self) -> Result<bool> {
-        const COMPACT_THRESHOLD: u64 = 16_000_000; // 16 MB
+    #[test]
+    fn test_snapshot_survives_compaction() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let options = Options {
+            segment_size: 256,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(dir.path(), options).expect("open failed");
+        for key_id in 0..20 {
+            store
+                .set(format!("key{}", key_id), format!("old{}", key_id))
+                .expect("set failed");
+        }
+        let snapshot = store.snapshot();
+
+        for key_id in 0..20 {
+            store
+                .set(format!("key{}", key_id), format!("new{}", key_id))
+                .expect("set failed");
+        }
+        store.remove("key0".to_owned()).expect("remove failed");
+        store.shared.log_compact().expect("compaction failed");
+        drop(store);
 
-        let cur_offset = self.log_file.current_file_offset()?;
-        if cur_offset < COMPACT_THRESHOLD {
-            return Ok(false);
+        for key_id in 0..20 {
+            assert_eq!(
+                snapshot.get(format!("key{}", key_id)).expect("get failed"),
+                Some(format!("old{}", key_id))
+            );
         }
+    }
 
-        let mut new_log_pointer_map = HashMap::new();
-        let mut retained_offsets: Vec<u64> = self.log_pointer_map.values().map(|&x| x).collect();
-        retained_offsets.sort_unstable();
+    #[test]
+    fn test_sequence_numbers_survive_compaction() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let options = Options {
+            segment_size: 256,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(dir.path(), options.clone()).expect("open failed");
+        // Every segment ends up with one live key, and overwrites of `k` that
+        // compaction drops along with their sequence numbers
+        for key in ["a", "b", "c", "d"] {
+            store
+                .set(key.to_owned(), "value".to_owned())
+                .expect("set failed");
+            for _ in 0..7 {
+                store
+                    .set("k".to_owned(), "value".to_owned())
+                    .expect("set failed");
+            }
+        }
+        store
+            .set("z".to_owned(), "value".to_owned())
+            .expect("set failed");
+        store.shared.log_compact().expect("compaction failed");
+        let generations = store.generations();
+        drop(store);
 
-        self.log_file
-            .compact(&retained_offsets, |buf, cur_offset| {
-                let cmd: Command = serde_json::from_slice(buf).expect("no error");
-                let Command::Set(key, _) = cmd else {
-                    panic!("should not happen");
-                };
+        // The only record left unhinted is torn
+        let active_path = dir
+            .path()
+            .join(format!("{}.log", generations.last().unwrap()));
+        fs::OpenOptions::new()
+            .write(true)
+            .open(active_path)
+            .expect("open failed")
+            .set_len(10)
+            .expect("truncate failed");
 
-                new_log_pointer_map.insert(key, cur_offset);
-            })?;
+        let store = KvStore::open_with_options(dir.path(), options).expect("open failed");
+        assert_eq!(store.discarded_bytes(), 10);
+        assert!(store.shared.next_version.load(Ordering::SeqCst) > 32);
+    }
 
-        self.log_pointer_map = new_log_pointer_map;
+    #[test]
+    fn test_compaction_drops_expired_values() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let options = Options {
+            segment_size: 256,
+            ..Options::default()
+        };
+        let mut store =
+            KvStore::open_with_options(dir.path(), options.clone()).expect("open failed");
+        for key_id in 0..20 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", key_id))
+                .expect("set failed");
+        }
+        // The expired values shadow the older ones, which must not come back
+        for key_id in 0..10 {
+            store
+                .set_with_ttl(
+                    format!("key{}", key_id),
+                    format!("session{}", key_id),
+                    Duration::from_millis(50),
+                )
+                .expect("set failed");
+        }
+        thread::sleep(Duration::from_millis(100));
+        let size_before = store.shared.log.read().unwrap().size();
 
-        Ok(true)
+        store.shared.log_compact().expect("compaction failed");
+        assert!(store.shared.log.read().unwrap().size() < size_before);
+        for _ in 0..2 {
+            for key_id in 0..20 {
+                let expected = (key_id >= 10).then(|| format!("value{}", key_id));
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).expect("get failed"),
+                    expected
+                );
+            }
+            drop(store);
+            store = KvStore::open_with_options(dir.path(), options.clone()).expect("open failed");
+        }
     }
 }
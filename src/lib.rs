@@ -1,10 +1,40 @@
 // #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use batch::WriteBatch;
+pub use changes::{Change, Changes};
+pub use engine::KvsEngine;
+pub use error::KvsError;
 use failure::Error;
 pub use kv::KvStore;
+pub use memory_engine::MemoryKvsEngine;
+pub use options::{Durability, Options};
+pub use scan::{Scan, ScanBytes};
+pub use sled_engine::SledKvsEngine;
+pub use snapshot::Snapshot;
+pub use stats::Stats;
+pub use transaction::Transaction;
+pub use typed::TypedStore;
+pub use watch::Event;
 
 /// abc
 pub type Result<T> = std::result::Result<T, Error>;
 
+mod batch;
+mod changes;
+mod command;
+mod engine;
+mod error;
+mod hint;
 mod kv;
+mod log;
+mod memory_engine;
+mod options;
+mod record;
+mod scan;
+mod sled_engine;
+mod snapshot;
+mod stats;
+mod transaction;
+mod typed;
+mod watch;
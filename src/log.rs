@@ -0,0 +1,1238 @@
+//! Segmented append-only log.
+//!
+//! The log is a sequence of numbered segment files `<generation>.log` in the store
+//! directory. Records are only appended to the segment with the highest
+//! generation, the active one. Once it grows past the configured segment size a new
+//! active segment is started and the previous one becomes immutable. Immutable
+//! segments are only ever rewritten as a whole by compaction, one at a time, which
+//! also leaves a `<generation>.hint` file next to them (see `hint`).
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use failure::format_err;
+
+use crate::{
+    Durability, Options, Result,
+    command::{self, Command},
+    hint::{self, HintEntry},
+    record::{self, HEADER_SIZE, Header, RecordType},
+};
+
+/// Location of a framed record inside the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LogPointer {
+    /// Generation of the segment holding the record.
+    pub(crate) generation: u64,
+    /// Offset of the record header.
+    pub(crate) offset: u64,
+    /// Length of the whole record, header included.
+    pub(crate) len: u64,
+}
+
+/// What `Log::replay` hands back, segment by segment.
+pub(crate) enum Replayed<'a> {
+    /// A record read from a segment.
+    Record(LogPointer, RecordType, &'a [u8]),
+    /// The highest sequence number found in a hint file, fed before its entries.
+    /// Records carrying it may have been dropped from the segment since.
+    HintMaxSeq(u64),
+    /// An entry of a hint file, standing in for the record it points to.
+    Hint(LogPointer, HintEntry),
+}
+
+struct Segment {
+    // Shared with the snapshots that pinned the segment
+    reader: Arc<File>,
+    len: u64,
+    // Bytes taken by records that have been overwritten or removed since
+    stale: u64,
+}
+
+pub(crate) struct Log {
+    dir_path: PathBuf,
+    segments: BTreeMap<u64, Segment>,
+    active_generation: u64,
+    writer: File,
+    segment_size: u64,
+    durability: Durability,
+    last_sync: Instant,
+    // Whether some appended records have not been synced yet
+    dirty: bool,
+}
+
+impl Log {
+    pub(crate) fn open(dir_path: &Path, options: &Options) -> Result<Log> {
+        recover_compactions(dir_path)?;
+        migrate_head_log(dir_path)?;
+
+        let mut generations = list_generations(dir_path)?;
+        if generations.is_empty() {
+            generations.push(1);
+        }
+        let active_generation = *generations.last().unwrap();
+        let writer = open_writer(dir_path, active_generation)?;
+
+        let mut segments = BTreeMap::new();
+        for generation in generations {
+            let reader = Arc::new(File::open(segment_path(dir_path, generation))?);
+            let len = reader.metadata()?.len();
+            segments.insert(
+                generation,
+                Segment {
+                    reader,
+                    len,
+                    stale: 0,
+                },
+            );
+        }
+
+        Ok(Log {
+            dir_path: dir_path.to_path_buf(),
+            segments,
+            active_generation,
+            writer,
+            segment_size: options.segment_size,
+            durability: options.durability,
+            last_sync: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    /// Feeds every record of every segment to `apply`, in log order.
+    ///
+    /// Segments with an up to date hint file are not read, the entries of the hint
+    /// are fed instead. An incomplete record at the end of the active segment, left
+    /// behind by a torn write, is cut off. Returns the number of bytes discarded
+    /// that way. Any other damage is an error, so that no valid record is lost.
+    pub(crate) fn replay(&mut self, mut apply: impl FnMut(Replayed) -> Result<()>) -> Result<u64> {
+        let generations: Vec<u64> = self.segments.keys().copied().collect();
+        let mut discarded = 0;
+        for generation in generations {
+            if let Some((max_seq, entries)) = self.read_hint(generation) {
+                apply(Replayed::HintMaxSeq(max_seq))?;
+                for entry in entries {
+                    let pointer = LogPointer {
+                        generation,
+                        offset: entry.offset,
+                        len: entry.len,
+                    };
+                    apply(Replayed::Hint(pointer, entry))?;
+                }
+                continue;
+            }
+
+            let mut reader = SegmentReader::open(&self.dir_path, generation)?;
+            while let Some((pointer, record_type, payload)) = reader.next()? {
+                unbatch(
+                    pointer,
+                    record_type,
+                    &payload,
+                    |pointer, record_type, payload| {
+                        apply(Replayed::Record(pointer, record_type, payload))
+                    },
+                )?;
+            }
+            if reader.offset < reader.len {
+                if generation != self.active_generation {
+                    return Err(format_err!(
+                        "segment {} is damaged at offset {}",
+                        generation,
+                        reader.offset
+                    ));
+                }
+                discarded = reader.len - reader.offset;
+                self.writer.set_len(reader.offset)?;
+                self.writer.sync_all()?;
+                self.segments.get_mut(&generation).unwrap().len = reader.offset;
+            }
+        }
+        Ok(discarded)
+    }
+
+    /// Loads the hint file of segment `generation`.
+    ///
+    /// Returns `None` when there is no usable hint: it is missing, damaged, or was
+    /// written for another version of the segment. The segment must then be read.
+    /// Otherwise returns the highest sequence number of the segment and the entries.
+    fn read_hint(&self, generation: u64) -> Option<(u64, Vec<HintEntry>)> {
+        let buf = fs::read(hint_path(&self.dir_path, generation)).ok()?;
+        let (segment_len, max_seq, entries) = hint::decode(&buf).ok()?;
+        (segment_len == self.segments.get(&generation)?.len).then_some((max_seq, entries))
+    }
+
+    /// Highest sequence number recorded in the hint of segment `generation`, 0 if it
+    /// has no usable hint.
+    pub(crate) fn hint_max_seq(&self, generation: u64) -> u64 {
+        self.read_hint(generation).map_or(0, |(max_seq, _)| max_seq)
+    }
+
+    /// Frames `payload` and appends it to the active segment.
+    pub(crate) fn append(&mut self, record_type: RecordType, payload: &[u8]) -> Result<LogPointer> {
+        let buf = record::encode(record_type, payload)?;
+        self.write_record(&buf)
+    }
+
+    /// Appends `payloads` as a single batch record, so that after a crash either all
+    /// or none of them are replayed.
+    ///
+    /// Each payload is framed as a record of its own inside the batch, and the
+    /// returned pointers refer to those, so they are read like any other record.
+    pub(crate) fn append_batch(
+        &mut self,
+        record_type: RecordType,
+        payloads: &[Vec<u8>],
+    ) -> Result<Vec<LogPointer>> {
+        let mut inner = Vec::new();
+        let mut ranges = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            let buf = record::encode(record_type, payload)?;
+            ranges.push((inner.len() as u64, buf.len() as u64));
+            inner.extend_from_slice(&buf);
+        }
+
+        let batch = self.write_record(&record::encode(RecordType::Batch, &inner)?)?;
+        Ok(ranges
+            .into_iter()
+            .map(|(offset, len)| LogPointer {
+                generation: batch.generation,
+                offset: batch.offset + HEADER_SIZE as u64 + offset,
+                len,
+            })
+            .collect())
+    }
+
+    /// Appends an already framed record to the active segment, starting a new
+    /// segment first if it would not fit.
+    fn write_record(&mut self, buf: &[u8]) -> Result<LogPointer> {
+        let active_len = self.segments[&self.active_generation].len;
+        if active_len > 0 && active_len + buf.len() as u64 > self.segment_size {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(buf)?;
+        let segment = self.segments.get_mut(&self.active_generation).unwrap();
+        let pointer = LogPointer {
+            generation: self.active_generation,
+            offset: segment.len,
+            len: buf.len() as u64,
+        };
+        segment.len += pointer.len;
+        self.dirty = true;
+        self.sync_for_durability()?;
+        Ok(pointer)
+    }
+
+    /// Seals the active segment and starts a new one.
+    fn rotate(&mut self) -> Result<()> {
+        // Immutable segments are always fully on disk, whatever the durability mode
+        if !self.is_synced() {
+            self.sync()?;
+        }
+
+        let generation = self.active_generation + 1;
+        self.writer = open_writer(&self.dir_path, generation)?;
+        let reader = Arc::new(File::open(segment_path(&self.dir_path, generation))?);
+        self.segments.insert(
+            generation,
+            Segment {
+                reader,
+                len: 0,
+                stale: 0,
+            },
+        );
+        self.active_generation = generation;
+        Ok(())
+    }
+
+    /// Syncs appended records to disk if the durability mode asks for it.
+    fn sync_for_durability(&mut self) -> Result<()> {
+        let due = match self.durability {
+            Durability::Always => true,
+            Durability::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            Durability::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Whether every record appended so far was synced to disk.
+    pub(crate) fn is_synced(&self) -> bool {
+        !self.dirty
+    }
+
+    /// Forces the records appended so far to disk.
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.writer.sync_data()?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Reads and verifies the record `pointer` refers to, returns its type and payload.
+    ///
+    /// Reads are positional, so any number of them can run at the same time.
+    pub(crate) fn read(&self, pointer: LogPointer) -> Result<(RecordType, Vec<u8>)> {
+        let segment = self.segments.get(&pointer.generation);
+        read_record(segment.map(|segment| &*segment.reader), pointer)
+    }
+
+    /// Keeps the segments readable as they are now, whatever compaction does to
+    /// them later.
+    pub(crate) fn pin(&self) -> PinnedSegments {
+        PinnedSegments {
+            files: self
+                .segments
+                .iter()
+                .map(|(&generation, segment)| (generation, Arc::clone(&segment.reader)))
+                .collect(),
+        }
+    }
+
+    /// Total size of all segments in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        self.segments.values().map(|segment| segment.len).sum()
+    }
+
+    /// Total size of the records that have been marked stale.
+    pub(crate) fn stale_bytes(&self) -> u64 {
+        self.segments.values().map(|segment| segment.stale).sum()
+    }
+
+    /// Records that `bytes` of segment `generation` are no longer needed.
+    pub(crate) fn add_stale(&mut self, generation: u64, bytes: u64) {
+        if let Some(segment) = self.segments.get_mut(&generation) {
+            segment.stale += bytes;
+        }
+    }
+
+    /// Generations of all segments, oldest first.
+    pub(crate) fn generations(&self) -> Vec<u64> {
+        self.segments.keys().copied().collect()
+    }
+
+    /// Generations of the immutable segments in which stale records take at least
+    /// `stale_ratio` of the space, oldest first.
+    pub(crate) fn stale_generations(&self, stale_ratio: f64) -> Vec<u64> {
+        self.segments
+            .iter()
+            .filter(|&(&generation, segment)| {
+                generation != self.active_generation
+                    && segment.stale > 0
+                    && segment.stale as f64 >= stale_ratio * segment.len as f64
+            })
+            .map(|(&generation, _)| generation)
+            .collect()
+    }
+
+    /// Puts a segment rewritten by `rewrite_segment` in place of the original.
+    ///
+    /// `stale` is how much of the rewritten segment went stale while it was being
+    /// built. The hint of the original segment is removed first since it no longer
+    /// describes it. A segment left without records is deleted.
+    pub(crate) fn install_segment<T>(
+        &mut self,
+        compacted: &CompactedSegment<T>,
+        stale: u64,
+    ) -> Result<()> {
+        let generation = compacted.generation;
+        if generation == self.active_generation {
+            return Err(format_err!("cannot compact the active segment"));
+        }
+        if !compacted.changed {
+            // Nothing was dropped, only records that went stale meanwhile remain
+            if let Some(segment) = self.segments.get_mut(&generation) {
+                segment.stale = stale;
+            }
+            return Ok(());
+        }
+
+        let hint_path = hint_path(&self.dir_path, generation);
+        if hint_path.exists() {
+            fs::remove_file(&hint_path)?;
+        }
+
+        let temp_path = compact_path(&self.dir_path, generation);
+        let segment_path = segment_path(&self.dir_path, generation);
+        if compacted.kept.is_empty() {
+            fs::remove_file(&temp_path)?;
+            fs::remove_file(&segment_path)?;
+            self.segments.remove(&generation);
+        } else {
+            fs::rename(&temp_path, &segment_path)?;
+            let reader = Arc::new(File::open(&segment_path)?);
+            self.segments.insert(
+                generation,
+                Segment {
+                    reader,
+                    len: compacted.len,
+                    stale,
+                },
+            );
+        }
+        // The rename must be durable before the caller starts relying on the new offsets
+        sync_dir(&self.dir_path)
+    }
+}
+
+/// Handles on the segment files as they were when `Log::pin` was called.
+///
+/// Compaction replaces a segment file by renaming another over it, or deletes it.
+/// Neither affects a handle opened before, so records stay readable through these
+/// at the location they had when pinned.
+#[derive(Clone)]
+pub(crate) struct PinnedSegments {
+    files: BTreeMap<u64, Arc<File>>,
+}
+
+impl PinnedSegments {
+    /// Reads and verifies the record `pointer` refers to, returns its type and payload.
+    pub(crate) fn read(&self, pointer: LogPointer) -> Result<(RecordType, Vec<u8>)> {
+        read_record(
+            self.files.get(&pointer.generation).map(|file| &**file),
+            pointer,
+        )
+    }
+}
+
+/// Reads the record `pointer` refers to from `file`, the segment it points into.
+fn read_record(file: Option<&File>, pointer: LogPointer) -> Result<(RecordType, Vec<u8>)> {
+    let file = file.ok_or_else(|| format_err!("segment {} not found", pointer.generation))?;
+    let mut buf = vec![0; pointer.len as usize];
+    read_exact_at(file, &mut buf, pointer.offset)?;
+    let (record_type, _) = record::decode(&buf).map_err(|err| {
+        format_err!(
+            "{} in segment {} at offset {}",
+            err,
+            pointer.generation,
+            pointer.offset
+        )
+    })?;
+    Ok((record_type, buf.split_off(HEADER_SIZE)))
+}
+
+/// A segment rewritten by `rewrite_segment`, waiting for `Log::install_segment`.
+pub(crate) struct CompactedSegment<T> {
+    pub(crate) generation: u64,
+    /// Tag, old location and new location of every record kept.
+    pub(crate) kept: Vec<(T, LogPointer, LogPointer)>,
+    /// Length of the rewritten segment.
+    pub(crate) len: u64,
+    /// Whether any record was dropped. If not, no new file was written.
+    pub(crate) changed: bool,
+}
+
+/// Rewrites the immutable segment `generation`, keeping only the records `retain`
+/// picks.
+///
+/// `retain` sees every record of the segment in order, along with its current
+/// location, and returns a tag for each record to keep. The rewritten segment is
+/// built in `<generation>.log.compact` and synced, but it does not replace the
+/// original until `Log::install_segment`, so a crash leaves either the old or the
+/// new segment in place. This only touches files that nothing else writes, so it
+/// can run without holding up the `Log`.
+pub(crate) fn rewrite_segment<T>(
+    dir_path: &Path,
+    generation: u64,
+    mut retain: impl FnMut(LogPointer, RecordType, &[u8]) -> Result<Option<T>>,
+) -> Result<CompactedSegment<T>> {
+    let temp_path = compact_path(dir_path, generation);
+    let mut new_file = BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?,
+    );
+
+    let mut reader = SegmentReader::open(dir_path, generation)?;
+    let mut kept = Vec::new();
+    let mut new_len = 0;
+    while let Some((pointer, record_type, payload)) = reader.next()? {
+        // Batches only matter until they are completely written, which they are
+        // in an immutable segment, so their records are kept on their own
+        unbatch(
+            pointer,
+            record_type,
+            &payload,
+            |pointer, record_type, payload| {
+                if let Some(tag) = retain(pointer, record_type, payload)? {
+                    let buf = record::encode(record_type, payload)?;
+                    new_file.write_all(&buf)?;
+                    let new_pointer = LogPointer {
+                        generation,
+                        offset: new_len,
+                        len: buf.len() as u64,
+                    };
+                    kept.push((tag, pointer, new_pointer));
+                    new_len += new_pointer.len;
+                }
+                Ok(())
+            },
+        )?;
+    }
+    if reader.offset < reader.len {
+        return Err(format_err!(
+            "segment {} is damaged at offset {}",
+            generation,
+            reader.offset
+        ));
+    }
+
+    let new_file = new_file.into_inner().map_err(|err| err.into_error())?;
+    let changed = new_len != reader.len;
+    if changed {
+        // The new file must be durable before it replaces the segment
+        new_file.sync_all()?;
+    } else {
+        fs::remove_file(&temp_path)?;
+    }
+
+    Ok(CompactedSegment {
+        generation,
+        kept,
+        len: new_len,
+        changed,
+    })
+}
+
+/// Writes the hint file of the immutable segment `generation`, `segment_len` bytes
+/// long, whose highest sequence number is `max_seq`.
+///
+/// The file is built aside and renamed into place, so a crash never leaves a
+/// partial hint behind.
+pub(crate) fn write_hint(
+    dir_path: &Path,
+    generation: u64,
+    segment_len: u64,
+    max_seq: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let buf = hint::encode(segment_len, max_seq, entries)?;
+
+    let hint_path = hint_path(dir_path, generation);
+    let temp_path = hint_path.with_extension("hint.tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &hint_path)?;
+    sync_dir(dir_path)
+}
+
+/// Feeds the records of segment `generation` to `f` in order, batches expanded,
+/// for as long as `f` returns `true`.
+///
+/// This is meant for readers running alongside the store: a segment deleted by
+/// compaction reads as empty, and reading stops at an incomplete record, which may
+/// be one still being appended to the active segment.
+pub(crate) fn read_segment(
+    dir_path: &Path,
+    generation: u64,
+    mut f: impl FnMut(RecordType, &[u8]) -> Result<bool>,
+) -> Result<()> {
+    let file = match File::open(segment_path(dir_path, generation)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = SegmentReader::new(file, generation)?;
+    let mut more = true;
+    while more && let Some((pointer, record_type, payload)) = reader.next()? {
+        unbatch(pointer, record_type, &payload, |_, record_type, payload| {
+            if more {
+                more = f(record_type, payload)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        self.writer.flush().expect("flush WAL error");
+        if self.dirty && self.durability != Durability::Never {
+            self.sync().expect("sync WAL error");
+        }
+    }
+}
+
+/// Feeds the record at `pointer` to `f`, or each record it holds if it is a batch.
+fn unbatch(
+    pointer: LogPointer,
+    record_type: RecordType,
+    payload: &[u8],
+    mut f: impl FnMut(LogPointer, RecordType, &[u8]) -> Result<()>,
+) -> Result<()> {
+    if record_type != RecordType::Batch {
+        return f(pointer, record_type, payload);
+    }
+
+    let mut offset = 0;
+    while offset < payload.len() {
+        let inner = &payload[offset..];
+        let Some(header) = inner.first_chunk::<HEADER_SIZE>() else {
+            return Err(format_err!(
+                "batch in segment {} is malformed",
+                pointer.generation
+            ));
+        };
+        let record_len = Header::decode(header).record_len() as usize;
+        if inner.len() < record_len {
+            return Err(format_err!(
+                "batch in segment {} is malformed",
+                pointer.generation
+            ));
+        }
+        let (inner_type, inner_payload) = record::decode(&inner[..record_len])?;
+        if inner_type == RecordType::Batch {
+            return Err(format_err!(
+                "nested batch in segment {}",
+                pointer.generation
+            ));
+        }
+        let inner_pointer = LogPointer {
+            generation: pointer.generation,
+            offset: pointer.offset + (HEADER_SIZE + offset) as u64,
+            len: record_len as u64,
+        };
+        f(inner_pointer, inner_type, inner_payload)?;
+        offset += record_len;
+    }
+    Ok(())
+}
+
+/// Sequential reader over the records of one segment.
+struct SegmentReader {
+    reader: BufReader<File>,
+    generation: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl SegmentReader {
+    fn open(dir_path: &Path, generation: u64) -> Result<SegmentReader> {
+        SegmentReader::new(File::open(segment_path(dir_path, generation))?, generation)
+    }
+
+    fn new(file: File, generation: u64) -> Result<SegmentReader> {
+        let len = file.metadata()?.len();
+        Ok(SegmentReader {
+            reader: BufReader::new(file),
+            generation,
+            offset: 0,
+            len,
+        })
+    }
+
+    /// Reads the next record and advances past it.
+    ///
+    /// Returns `None` once the end of the segment is reached. An incomplete record
+    /// at the tail, left behind by a torn write, also ends the segment: `offset` is
+    /// left at its start so the caller can tell and cut it off. A record whose
+    /// length points past the end while other records follow is damaged instead,
+    /// which is an error.
+    fn next(&mut self) -> Result<Option<(LogPointer, RecordType, Vec<u8>)>> {
+        if self.len - self.offset < HEADER_SIZE as u64 {
+            // Either a clean end or a torn header
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        self.reader.read_exact(&mut header)?;
+        let header = Header::decode(&header);
+        let end = self.offset + header.record_len();
+        if end > self.len {
+            if self.tail_holds_records(&header)? {
+                return Err(format_err!(
+                    "segment {} is damaged at offset {}",
+                    self.generation,
+                    self.offset
+                ));
+            }
+            return Ok(None);
+        }
+
+        let mut payload = vec![0; header.len as usize];
+        self.reader.read_exact(&mut payload)?;
+        let record_type = match header.verify(&payload) {
+            Ok(record_type) => record_type,
+            Err(_) if end == self.len || self.is_zero_filled()? => return Ok(None),
+            Err(err) => {
+                return Err(format_err!(
+                    "{} in segment {} at offset {}",
+                    err,
+                    self.generation,
+                    self.offset
+                ));
+            }
+        };
+
+        let pointer = LogPointer {
+            generation: self.generation,
+            offset: self.offset,
+            len: header.record_len(),
+        };
+        self.offset = end;
+        Ok(Some((pointer, record_type, payload)))
+    }
+
+    /// Checks whether more records follow the header just read, whose length
+    /// points past the end of the segment.
+    ///
+    /// A torn write only leaves part of a single record behind, so any record found
+    /// after it means the length is damaged rather than the write torn. The records
+    /// a torn batch starts with belong to it and are skipped. A value can hold
+    /// anything, records included, so the tail of a command agreeing with its
+    /// length is not looked into.
+    fn tail_holds_records(&mut self, header: &Header) -> Result<bool> {
+        let mut tail = Vec::new();
+        (&mut self.reader)
+            .take(self.len - self.offset - HEADER_SIZE as u64)
+            .read_to_end(&mut tail)?;
+        let mut start = 0;
+        if header.record_type() == Some(RecordType::Batch) {
+            while let Some((record_type, len)) = leading_record(&tail[start..])
+                && record_type != RecordType::Batch
+            {
+                start += len;
+            }
+        }
+
+        let rest = &tail[start..];
+        let (record_type, len, payload) = match header.record_type() {
+            Some(RecordType::Batch) => match rest.first_chunk::<HEADER_SIZE>() {
+                Some(inner) => {
+                    let inner = Header::decode(inner);
+                    (inner.record_type(), inner.len, &rest[HEADER_SIZE..])
+                }
+                None => return Ok(false),
+            },
+            record_type => (record_type, header.len, rest),
+        };
+        if record_type == Some(RecordType::Command) && command::prefix_fits(payload, len.into()) {
+            return Ok(false);
+        }
+        Ok((0..rest.len()).any(|start| leading_record(&rest[start..]).is_some()))
+    }
+
+    /// Checks whether nothing but zeroes is left to read, which is what a crash can
+    /// leave behind after the file size was extended but before data landed.
+    fn is_zero_filled(&mut self) -> Result<bool> {
+        let mut chunk = [0; 4096];
+        loop {
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(true);
+            }
+            if chunk[..n].iter().any(|&b| b != 0) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+fn segment_path(dir_path: &Path, generation: u64) -> PathBuf {
+    dir_path.join(format!("{}.log", generation))
+}
+
+fn hint_path(dir_path: &Path, generation: u64) -> PathBuf {
+    dir_path.join(format!("{}.hint", generation))
+}
+
+fn compact_path(dir_path: &Path, generation: u64) -> PathBuf {
+    dir_path.join(format!("{}.log.compact", generation))
+}
+
+fn open_writer(dir_path: &Path, generation: u64) -> Result<File> {
+    let writer = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(segment_path(dir_path, generation))?;
+    sync_dir(dir_path)?;
+    Ok(writer)
+}
+
+/// Fills `buf` with the bytes of `file` starting at `offset`, without moving the
+/// file cursor, so that concurrent reads on one handle do not interfere.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generations of the segments in `dir_path`, oldest first.
+fn list_generations(dir_path: &Path) -> Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let file_name = entry?.file_name();
+        let generation = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|stem| stem.parse::<u64>().ok());
+        if let Some(generation) = generation {
+            generations.push(generation);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// Deals with `*.log.compact` and `*.hint.tmp` files left behind by a compaction
+/// that crashed.
+///
+/// A compacted file only replaces its segment through an atomic rename, so as long
+/// as the segment exists it is authoritative and the leftover is discarded. If the
+/// segment is missing, the compacted file is all that is left and the rename is
+/// finished instead.
+fn recover_compactions(dir_path: &Path) -> Result<()> {
+    let mut recovered = false;
+    for entry in fs::read_dir(dir_path)? {
+        let temp_path = entry?.path();
+        // Hints are cheap to rebuild, partial ones are simply dropped
+        if temp_path
+            .to_str()
+            .is_some_and(|path| path.ends_with(".hint.tmp"))
+        {
+            fs::remove_file(&temp_path)?;
+            recovered = true;
+            continue;
+        }
+
+        let Some(target) = temp_path
+            .to_str()
+            .and_then(|path| path.strip_suffix(".compact"))
+            .filter(|path| path.ends_with(".log"))
+            .map(PathBuf::from)
+        else {
+            continue;
+        };
+
+        if target.exists() {
+            fs::remove_file(&temp_path)?;
+        } else {
+            File::open(&temp_path)?.sync_all()?;
+            fs::rename(&temp_path, &target)?;
+        }
+        recovered = true;
+    }
+    if recovered {
+        sync_dir(dir_path)?;
+    }
+    Ok(())
+}
+
+/// Stores created before segments were introduced keep everything in `head.log`,
+/// which simply becomes the first segment.
+///
+/// The first versions wrote it as lines of JSON instead of framed records. Such a
+/// file is framed first, in a copy that atomically replaces it, so a crash leaves
+/// either version in place.
+fn migrate_head_log(dir_path: &Path) -> Result<()> {
+    let head_path = dir_path.join("head.log");
+    if !head_path.exists() {
+        return Ok(());
+    }
+    if !list_generations(dir_path)?.is_empty() {
+        return Err(format_err!("found both head.log and log segments"));
+    }
+
+    let buf = fs::read(&head_path)?;
+    // A framed record may start with `{` too, but then it is valid
+    if buf.first() == Some(&b'{') && leading_record(&buf).is_none() {
+        let temp_path = dir_path.join("head.log.framed");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&frame_json_lines(&buf)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &head_path)?;
+    }
+    fs::rename(&head_path, segment_path(dir_path, 1))?;
+    sync_dir(dir_path)
+}
+
+/// Frames every line of a `head.log` holding one JSON command per line.
+///
+/// Fails if a line is not a command: nothing tells a damaged line from a torn write
+/// in that format, so it is left for the user to deal with.
+fn frame_json_lines(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut framed = Vec::with_capacity(buf.len());
+    for (index, line) in buf.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        Command::decode(RecordType::JsonCommand, line)
+            .map_err(|err| format_err!("head.log is damaged at line {}: {}", index + 1, err))?;
+        framed.extend_from_slice(&record::encode(RecordType::JsonCommand, line)?);
+    }
+    Ok(framed)
+}
+
+/// Type and length of the valid record `buf` starts with, if it does.
+fn leading_record(buf: &[u8]) -> Option<(RecordType, usize)> {
+    let header = Header::decode(buf.first_chunk::<HEADER_SIZE>()?);
+    let record_len = usize::try_from(header.record_len()).ok()?;
+    let record_type = header.verify(buf.get(HEADER_SIZE..record_len)?).ok()?;
+    Some((record_type, record_len))
+}
+
+/// Flushes directory entries (file creations and renames) of `dir_path` to disk.
+pub(crate) fn sync_dir(dir_path: &Path) -> Result<()> {
+    File::open(dir_path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+    };
+
+    use super::{
+        Command, HintEntry, Log, LogPointer, RecordType, Replayed, record, rewrite_segment,
+        segment_path, write_hint,
+    };
+    use crate::Options;
+
+    fn open_log(dir: &tempfile::TempDir, segment_size: u64) -> Log {
+        let options = Options {
+            segment_size,
+            ..Options::default()
+        };
+        Log::open(dir.path(), &options).expect("open log failed")
+    }
+
+    fn replay(log: &mut Log) -> (Vec<(LogPointer, Vec<u8>)>, u64) {
+        let mut records = Vec::new();
+        let discarded = log
+            .replay(|replayed| {
+                match replayed {
+                    Replayed::Record(pointer, _, payload) => {
+                        records.push((pointer, payload.to_vec()))
+                    }
+                    Replayed::Hint(pointer, entry) => {
+                        records.push((pointer, entry.key.into_bytes()))
+                    }
+                    Replayed::HintMaxSeq(_) => {}
+                }
+                Ok(())
+            })
+            .expect("replay failed");
+        (records, discarded)
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 1 << 20);
+        let large = "line1\nline2\n".repeat(10_000).into_bytes();
+        let pointer = log
+            .append(RecordType::Command, &large)
+            .expect("append failed");
+        log.append(RecordType::Command, b"second")
+            .expect("append failed");
+        assert_eq!(log.read(pointer).expect("read failed").1, large);
+        drop(log);
+
+        let mut log = open_log(&dir, 1 << 20);
+        let (records, discarded) = replay(&mut log);
+        assert_eq!(discarded, 0);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], (pointer, large));
+        assert_eq!(records[1].1, b"second");
+    }
+
+    #[test]
+    fn test_rotate_segments() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 64);
+        let pointers: Vec<LogPointer> = (0..10)
+            .map(|i| {
+                log.append(RecordType::Command, format!("record{:024}", i).as_bytes())
+                    .expect("append failed")
+            })
+            .collect();
+        assert_eq!(pointers[0].generation, 1);
+        assert_eq!(pointers[9].generation, 10);
+        assert_eq!(log.generations(), (1..=10).collect::<Vec<u64>>());
+        drop(log);
+
+        let mut log = open_log(&dir, 64);
+        let (records, _) = replay(&mut log);
+        let replayed: Vec<LogPointer> = records.iter().map(|(pointer, _)| *pointer).collect();
+        assert_eq!(replayed, pointers);
+    }
+
+    fn compact<T>(
+        log: &mut Log,
+        dir: &tempfile::TempDir,
+        generation: u64,
+        retain: impl FnMut(LogPointer, RecordType, &[u8]) -> crate::Result<Option<T>>,
+    ) -> Vec<(T, LogPointer)> {
+        let compacted = rewrite_segment(dir.path(), generation, retain).expect("rewrite failed");
+        log.install_segment(&compacted, 0).expect("install failed");
+        compacted
+            .kept
+            .into_iter()
+            .map(|(tag, _, pointer)| (tag, pointer))
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_segment() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 64);
+        for i in 0..4 {
+            log.append(RecordType::Command, format!("record{}", i).as_bytes())
+                .expect("append failed");
+        }
+        log.append(RecordType::Command, &[0; 64])
+            .expect("append failed");
+        assert_eq!(log.generations(), vec![1, 2]);
+
+        let kept = compact(&mut log, &dir, 1, |_, _, payload| {
+            Ok((payload != b"record1").then_some(0))
+        });
+        assert_eq!(kept.len(), 3);
+        assert_eq!(log.read(kept[1].1).expect("read failed").1, b"record2");
+
+        // Dropping every record deletes the segment
+        let kept = compact(&mut log, &dir, 1, |_, _, _| Ok(None::<()>));
+        assert!(kept.is_empty());
+        assert!(!segment_path(dir.path(), 1).exists());
+
+        let compacted =
+            rewrite_segment(dir.path(), 2, |_, _, _| Ok(None::<()>)).expect("rewrite failed");
+        assert!(log.install_segment(&compacted, 0).is_err());
+    }
+
+    #[test]
+    fn test_append_batch() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 64);
+        let single = log
+            .append(RecordType::Command, b"single")
+            .expect("append failed");
+        let payloads = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let batch = log
+            .append_batch(RecordType::Command, &payloads)
+            .expect("append batch failed");
+        for (pointer, payload) in batch.iter().zip(&payloads) {
+            assert_eq!(&log.read(*pointer).expect("read failed").1, payload);
+        }
+
+        let (records, _) = replay(&mut log);
+        let expected: Vec<(LogPointer, Vec<u8>)> = std::iter::once((single, b"single".to_vec()))
+            .chain(batch.iter().copied().zip(payloads.iter().cloned()))
+            .collect();
+        assert_eq!(records, expected);
+
+        // A torn batch is dropped as a whole
+        let len = log.size();
+        log.writer.set_len(len - 1).expect("truncate failed");
+        drop(log);
+        let mut log = open_log(&dir, 64);
+        let (records, discarded) = replay(&mut log);
+        assert_eq!(records, vec![(single, b"single".to_vec())]);
+        assert_eq!(discarded, len - 1 - single.len);
+
+        // Compaction keeps the records of a batch on their own
+        let batch = log
+            .append_batch(RecordType::Command, &payloads)
+            .expect("append batch failed");
+        log.append(RecordType::Command, &[0; 64])
+            .expect("append failed");
+        assert_eq!(log.generations(), vec![1, 2]);
+        let kept = compact(&mut log, &dir, 1, |pointer, _, payload| {
+            Ok((payload != b"b").then_some(pointer))
+        });
+        let old_pointers: Vec<LogPointer> = kept.iter().map(|(old, _)| *old).collect();
+        assert_eq!(old_pointers, vec![single, batch[0], batch[2]]);
+        assert_eq!(log.read(kept[2].1).expect("read failed").1, b"c");
+        assert_eq!(log.size(), single.len + batch[0].len + batch[2].len + 73);
+    }
+
+    #[test]
+    fn test_replay_from_hint() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 64);
+        for i in 0..4 {
+            log.append(RecordType::Command, format!("record{}", i).as_bytes())
+                .expect("append failed");
+        }
+        log.append(RecordType::Command, b"active")
+            .expect("append failed");
+
+        let kept = compact(&mut log, &dir, 1, |_, _, payload| {
+            Ok((payload != b"record0").then(|| String::from_utf8(payload.to_vec()).unwrap()))
+        });
+        let entries: Vec<HintEntry> = kept
+            .into_iter()
+            .map(|(key, pointer)| HintEntry {
+                key,
+                offset: pointer.offset,
+                len: pointer.len,
+                tombstone: false,
+                expires_at: None,
+            })
+            .collect();
+        write_hint(dir.path(), 1, log.segments[&1].len, 4, &entries).expect("write hint failed");
+        drop(log);
+
+        // Hint entries replace the records of the compacted segment only
+        let mut log = open_log(&dir, 64);
+        let (records, _) = replay(&mut log);
+        let keys: Vec<&[u8]> = records.iter().map(|(_, key)| key.as_slice()).collect();
+        assert_eq!(
+            keys,
+            vec![b"record1", b"record2", b"record3", b"active".as_ref()]
+        );
+        assert_eq!(log.read(records[0].0).expect("read failed").1, b"record1");
+        drop(log);
+
+        // A stale hint is ignored
+        write_hint(dir.path(), 1, 1, 4, &entries).expect("write hint failed");
+        let mut log = open_log(&dir, 64);
+        let (records, _) = replay(&mut log);
+        assert_eq!(records.len(), 4);
+        assert_eq!(log.read(records[0].0).expect("read failed").1, records[0].1);
+    }
+
+    #[test]
+    fn test_read_corrupted_record() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 1 << 20);
+        let pointer = log
+            .append(RecordType::Command, b"payload")
+            .expect("append failed");
+        log.append(RecordType::Command, b"payload")
+            .expect("append failed");
+
+        // Flip the last payload byte of the first record
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(segment_path(dir.path(), 1))
+            .expect("open failed");
+        file.seek(SeekFrom::Start(pointer.len - 1))
+            .expect("seek failed");
+        file.write_all(b"X").expect("write failed");
+
+        assert!(log.read(pointer).is_err());
+        // A damaged record followed by valid ones is not a torn write
+        assert!(log.replay(|_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_damaged_length_in_active_segment() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 1 << 20);
+        let pointers: Vec<LogPointer> = (0..100)
+            .map(|i| {
+                log.append(RecordType::Command, format!("record{}", i).as_bytes())
+                    .expect("append failed")
+            })
+            .collect();
+        let len = log.size();
+        drop(log);
+
+        // Make the length of the second record point past the end of the segment
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(segment_path(dir.path(), 1))
+            .expect("open failed");
+        file.seek(SeekFrom::Start(pointers[1].offset + 6))
+            .expect("seek failed");
+        file.write_all(&[0xff]).expect("write failed");
+
+        let mut log = open_log(&dir, 1 << 20);
+        let err = log.replay(|_| Ok(())).expect_err("replay succeeded");
+        assert!(err.to_string().contains("damaged at offset"));
+        assert_eq!(log.size(), len);
+    }
+
+    #[test]
+    fn test_torn_value_holding_records() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let set = |key: &str, value: Vec<u8>, seq| {
+            Command::Set(key.to_owned(), value)
+                .encode(seq)
+                .expect("encode failed")
+        };
+        // The value is a copy of a log
+        let mut value = Vec::new();
+        for seq in 0..3 {
+            let payload = set("inner", b"value".to_vec(), seq);
+            value.extend(record::encode(RecordType::Command, &payload).expect("encode failed"));
+        }
+        value.extend_from_slice(b"more");
+
+        let mut log = open_log(&dir, 1 << 20);
+        let first = log
+            .append(RecordType::Command, &set("a", b"value".to_vec(), 1))
+            .expect("append failed");
+        let second = log
+            .append(RecordType::Command, &set("b", value.clone(), 2))
+            .expect("append failed");
+        log.append_batch(
+            RecordType::Command,
+            &[set("c", value.clone(), 3), set("d", value, 4)],
+        )
+        .expect("append batch failed");
+        drop(log);
+        let bytes = fs::read(segment_path(dir.path(), 1)).expect("read failed");
+
+        // Torn anywhere after the first record, whether in a command or a batch
+        for cut in first.len as usize + 1..bytes.len() {
+            fs::write(segment_path(dir.path(), 1), &bytes[..cut]).expect("write failed");
+            let mut log = open_log(&dir, 1 << 20);
+            let (records, discarded) = replay(&mut log);
+            let kept = if cut < (second.offset + second.len) as usize {
+                1
+            } else {
+                2
+            };
+            assert_eq!(records.len(), kept, "cut at {}", cut);
+            assert_eq!(log.size() + discarded, cut as u64);
+        }
+    }
+
+    #[test]
+    fn test_truncate_torn_tail() {
+        let dir = tempfile::TempDir::new().expect("create temp dir failed");
+        let mut log = open_log(&dir, 1 << 20);
+        let pointer = log
+            .append(RecordType::Command, b"payload")
+            .expect("append failed");
+        log.writer.write_all(&[0; 64]).expect("write failed");
+        drop(log);
+
+        let mut log = open_log(&dir, 1 << 20);
+        let (records, discarded) = replay(&mut log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(discarded, 64);
+        assert_eq!(log.size(), pointer.len);
+    }
+}
@@ -0,0 +1,42 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` that keeps everything in memory and persists nothing.
+///
+/// It behaves like `KvStore` otherwise, which makes it handy for tests and caches
+/// that do not need to outlive the process. Clones share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .remove(&key)
+            .ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+}
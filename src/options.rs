@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// When writes are forced to stable storage.
+///
+/// Every write is handed to the operating system before `set`/`remove` return, so
+/// it survives a crash of the process. The durability mode decides whether it also
+/// survives a power failure or kernel crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// `fsync` the log after every write. Slowest, nothing acknowledged is lost.
+    Always,
+    /// `fsync` the log at most the given interval after a write, so writes in
+    /// between are committed as a group. A background thread syncs the writes left
+    /// behind once they stop coming. On power failure, writes younger than the
+    /// interval may be lost.
+    Periodic(Duration),
+    /// Leave flushing to the operating system. Fastest, recent writes may be lost
+    /// on power failure.
+    #[default]
+    Never,
+}
+
+/// Settings used when opening a `KvStore`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{Durability, KvStore, Options};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let options = Options {
+///     durability: Durability::Always,
+///     ..Options::default()
+/// };
+/// let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// See `Durability`, defaults to `Durability::Never`.
+    pub durability: Durability,
+    /// Size in bytes past which the active log segment is sealed and a new one is
+    /// started, defaults to 4 MB.
+    pub segment_size: u64,
+    /// Compaction only starts once at least this many bytes of the log are taken by
+    /// overwritten or removed values, defaults to 16 MB.
+    pub compaction_min_stale_bytes: u64,
+    /// Compaction only starts once at least this fraction of the log is stale,
+    /// defaults to 0.5. It then rewrites the immutable segments that are at least
+    /// this stale themselves.
+    pub compaction_stale_ratio: f64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            durability: Durability::default(),
+            segment_size: 4_000_000,
+            compaction_min_stale_bytes: 16_000_000,
+            compaction_stale_ratio: 0.5,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Binary framing of log records.
+//!
+//! Every record in a log file is laid out as:
+//!
+//! ```text
+//! +-----------+------------+----------+---------------------+
+//! | crc (u32) | len (u32)  | type(u8) | payload (len bytes) |
+//! +-----------+------------+----------+---------------------+
+//! ```
+//!
+//! Integers are little endian. The checksum covers `len`, `type` and the payload,
+//! so a record whose header or body was damaged is rejected as a whole.
+
+use failure::format_err;
+
+use crate::Result;
+
+/// Size in bytes of the header preceding every payload.
+pub(crate) const HEADER_SIZE: usize = 9;
+
+/// Kind of payload carried by a record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RecordType {
+    /// A single `Command` serialized as JSON, only written by older versions.
+    JsonCommand = 1,
+    /// The content of a hint file written by older versions, which lacks the
+    /// sequence numbers of its segment. Such hints are ignored.
+    UnnumberedHint = 2,
+    /// Several complete records framed as one, so that they are written and
+    /// replayed all or none, see `Log::append_batch`.
+    Batch = 3,
+    /// A single `Command`, see `command`.
+    Command = 4,
+    /// The content of a hint file, see `hint`.
+    Hint = 5,
+}
+
+impl RecordType {
+    fn from_u8(value: u8) -> Option<RecordType> {
+        match value {
+            1 => Some(RecordType::JsonCommand),
+            2 => Some(RecordType::UnnumberedHint),
+            3 => Some(RecordType::Batch),
+            4 => Some(RecordType::Command),
+            5 => Some(RecordType::Hint),
+            _ => None,
+        }
+    }
+}
+
+/// Decoded record header.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    crc: u32,
+    /// Length of the payload following the header.
+    pub(crate) len: u32,
+    type_byte: u8,
+}
+
+impl Header {
+    /// Parses a header, the checksum is only verified later by `verify`.
+    pub(crate) fn decode(buf: &[u8; HEADER_SIZE]) -> Header {
+        let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        Header {
+            crc,
+            len,
+            type_byte: buf[8],
+        }
+    }
+
+    /// Record type the header announces, before the checksum vouched for it.
+    pub(crate) fn record_type(&self) -> Option<RecordType> {
+        RecordType::from_u8(self.type_byte)
+    }
+
+    /// Total size of the record on disk.
+    pub(crate) fn record_len(&self) -> u64 {
+        HEADER_SIZE as u64 + self.len as u64
+    }
+
+    /// Verifies the checksum against `payload` and returns the record type.
+    pub(crate) fn verify(&self, payload: &[u8]) -> Result<RecordType> {
+        if checksum(self.len, self.type_byte, payload) != self.crc {
+            return Err(format_err!("record checksum mismatch"));
+        }
+        RecordType::from_u8(self.type_byte)
+            .ok_or_else(|| format_err!("unknown record type {}", self.type_byte))
+    }
+}
+
+fn checksum(len: u32, type_byte: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    hasher.update(&[type_byte]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Frames `payload` into a record ready to be appended to a log file.
+pub(crate) fn encode(record_type: RecordType, payload: &[u8]) -> Result<Vec<u8>> {
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .map_err(|_| format_err!("record too large: {} bytes", payload.len()))?;
+    let type_byte = record_type as u8;
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&checksum(len, type_byte, payload).to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.push(type_byte);
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// Decodes a complete record as produced by `encode`, returns its type and payload.
+pub(crate) fn decode(buf: &[u8]) -> Result<(RecordType, &[u8])> {
+    let Some((header, payload)) = buf.split_first_chunk::<HEADER_SIZE>() else {
+        return Err(format_err!("record shorter than its header"));
+    };
+    let header = Header::decode(header);
+    if payload.len() != header.len as usize {
+        return Err(format_err!(
+            "record length mismatch: expected {} bytes, got {}",
+            header.len,
+            payload.len()
+        ));
+    }
+    let record_type = header.verify(payload)?;
+    Ok((record_type, payload))
+}
+
+/// Splits the next `N` bytes off `buf`, a payload being parsed.
+pub(crate) fn take<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N]> {
+    let (head, rest) = buf
+        .split_first_chunk::<N>()
+        .ok_or_else(|| format_err!("record payload truncated"))?;
+    *buf = rest;
+    Ok(*head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HEADER_SIZE, RecordType, decode, encode};
+
+    #[test]
+    fn test_encode_decode() {
+        let buf = encode(RecordType::Command, b"payload\nwith newline").expect("encode failed");
+        assert_eq!(buf.len(), HEADER_SIZE + 20);
+
+        let (record_type, payload) = decode(&buf).expect("decode failed");
+        assert_eq!(record_type, RecordType::Command);
+        assert_eq!(payload, b"payload\nwith newline");
+    }
+
+    #[test]
+    fn test_detect_corruption() {
+        let mut buf = encode(RecordType::Command, b"payload").expect("encode failed");
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        assert!(decode(&buf).is_err());
+
+        let buf = encode(RecordType::Command, b"payload").expect("encode failed");
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::Result;
+
+/// Something ordered key/value pairs can be scanned from.
+pub(crate) trait ScanSource {
+    /// Gets the first key/value pair whose key is within the bounds.
+    fn first_in_range(
+        &self,
+        start: Bound<&String>,
+        end: Bound<&String>,
+    ) -> Result<Option<(String, Vec<u8>)>>;
+}
+
+/// Iterator over the key/value pairs of a range of keys, in key order, with bytes
+/// values, returned by the `scan_bytes` and `scan_prefix_bytes` methods of `KvStore`
+/// and `Snapshot`.
+pub struct ScanBytes<'a> {
+    source: &'a dyn ScanSource,
+    start: Bound<String>,
+    end: Bound<String>,
+    // Every key returned starts with it, the scan stops at the first that does not
+    prefix: String,
+    done: bool,
+}
+
+impl<'a> ScanBytes<'a> {
+    /// Scans the keys within `range`.
+    pub(crate) fn range(
+        source: &'a dyn ScanSource,
+        range: impl RangeBounds<String>,
+    ) -> ScanBytes<'a> {
+        ScanBytes::new(
+            source,
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            String::new(),
+        )
+    }
+
+    /// Scans the keys starting with `prefix`.
+    pub(crate) fn prefix(source: &'a dyn ScanSource, prefix: String) -> ScanBytes<'a> {
+        ScanBytes::new(
+            source,
+            Bound::Included(prefix.clone()),
+            Bound::Unbounded,
+            prefix,
+        )
+    }
+
+    fn new(
+        source: &'a dyn ScanSource,
+        start: Bound<String>,
+        end: Bound<String>,
+        prefix: String,
+    ) -> ScanBytes<'a> {
+        // Looking up an empty range with inverted or excluded equal bounds panics
+        let done = match (&start, &end) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start > end,
+            _ => false,
+        };
+        ScanBytes {
+            source,
+            start,
+            end,
+            prefix,
+            done,
+        }
+    }
+}
+
+impl Iterator for ScanBytes<'_> {
+    type Item = Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self
+            .source
+            .first_in_range(self.start.as_ref(), self.end.as_ref())
+        {
+            Ok(Some((key, value))) if key.starts_with(&self.prefix) => {
+                self.start = Bound::Excluded(key.clone());
+                Some(Ok((key, value)))
+            }
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Iterator over the key/value pairs of a range of keys, in key order, with string
+/// values, returned by the `scan` and `scan_prefix` methods of `KvStore` and
+/// `Snapshot`.
+///
+/// A value that is not valid UTF-8 yields an error, and the scan goes on with the
+/// next key.
+pub struct Scan<'a> {
+    bytes: ScanBytes<'a>,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(bytes: ScanBytes<'a>) -> Scan<'a> {
+        Scan { bytes }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.bytes.next()? {
+            Ok(pair) => pair,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(
+            String::from_utf8(value)
+                .map(|value| (key, value))
+                .map_err(Into::into),
+        )
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use sled::Db;
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// Every write is flushed before returning, so that it survives the process exiting
+/// right after, the way the `kvs` binary does.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Opens the sled database stored in `path`, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine::new(sled::open(path)?))
+    }
+
+    /// Wraps an already opened sled database.
+    pub fn new(db: Db) -> SledKvsEngine {
+        SledKvsEngine { db }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
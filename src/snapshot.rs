@@ -0,0 +1,91 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    Result, Scan, ScanBytes,
+    kv::{self, Index},
+    log::PinnedSegments,
+    scan::ScanSource,
+};
+
+/// A read-only view of a `KvStore` frozen at the moment `KvStore::snapshot` was
+/// called.
+///
+/// It is independent of the store it was taken from: it can be cloned, sent to
+/// another thread, and outlive the store.
+///
+/// Values that expire are checked against the time the snapshot was taken, so one
+/// that was live then stays visible for as long as the snapshot is kept.
+#[derive(Clone)]
+pub struct Snapshot {
+    log_pointer_map: Index,
+    segments: PinnedSegments,
+    // Time the snapshot was taken, in milliseconds since the Unix epoch
+    now: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(log_pointer_map: Index, segments: PinnedSegments, now: u64) -> Snapshot {
+        Snapshot {
+            log_pointer_map,
+            segments,
+            now,
+        }
+    }
+
+    /// Gets the string value the key had when the snapshot was taken.
+    ///
+    /// Returns `None` if the key did not exist then, or if its value had expired.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the bytes value the key had when the snapshot was taken, like `get`.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        match kv::live_entry(&self.log_pointer_map, &key, self.now) {
+            Some(entry) => Ok(Some(kv::read_value(self.segments.read(entry.pointer)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterates in key order over the key/value pairs whose key is in `range`.
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Scan<'_> {
+        Scan::new(self.scan_bytes(range))
+    }
+
+    /// Iterates in key order over the key/value pairs whose key starts with
+    /// `prefix`.
+    pub fn scan_prefix(&self, prefix: impl Into<String>) -> Scan<'_> {
+        Scan::new(self.scan_prefix_bytes(prefix))
+    }
+
+    /// Iterates over the key/value pairs whose key is in `range`, like `scan`, with
+    /// bytes values.
+    pub fn scan_bytes(&self, range: impl RangeBounds<String>) -> ScanBytes<'_> {
+        ScanBytes::range(self, range)
+    }
+
+    /// Iterates over the key/value pairs whose key starts with `prefix`, like
+    /// `scan_prefix`, with bytes values.
+    pub fn scan_prefix_bytes(&self, prefix: impl Into<String>) -> ScanBytes<'_> {
+        ScanBytes::prefix(self, prefix.into())
+    }
+}
+
+impl ScanSource for Snapshot {
+    fn first_in_range(
+        &self,
+        start: Bound<&String>,
+        end: Bound<&String>,
+    ) -> Result<Option<(String, Vec<u8>)>> {
+        let Some((key, entry)) =
+            kv::first_live_in_range(&self.log_pointer_map, start, end, self.now)
+        else {
+            return Ok(None);
+        };
+        let value = kv::read_value(self.segments.read(entry.pointer)?)?;
+        Ok(Some((key.clone(), value)))
+    }
+}
@@ -0,0 +1,13 @@
+/// Figures about a `KvStore`, as returned by `KvStore::stats`.
+///
+/// Namespaces are not included, each has stats of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of keys that have a value.
+    pub keys: usize,
+    /// Size of the log on disk, in bytes.
+    pub log_bytes: u64,
+    /// Part of `log_bytes` taken by overwritten or removed values, which the next
+    /// compaction may free.
+    pub stale_bytes: u64,
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use crate::{KvStore, KvsError, Result};
+
+/// An optimistic read-modify-write transaction over a `KvStore`, started with
+/// `KvStore::begin`.
+///
+/// Reads see the store as it is when they happen, along with the transaction's own
+/// writes. Writes are buffered until `commit`, which applies them atomically, but
+/// only if none of the keys the transaction read was written in the meantime.
+/// Otherwise it fails with `KvsError::TransactionConflict` without applying
+/// anything, and the transaction can be retried from the start.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("counter".to_owned(), "1".to_owned()).unwrap();
+///
+/// let mut txn = store.begin();
+/// let counter: u64 = txn.get("counter".to_owned()).unwrap().unwrap().parse().unwrap();
+/// txn.set("counter".to_owned(), (counter + 1).to_string());
+/// txn.commit().unwrap();
+/// assert_eq!(store.get("counter".to_owned()).unwrap(), Some("2".to_owned()));
+/// ```
+pub struct Transaction<'a> {
+    store: &'a KvStore,
+    // Version of every key read, `None` if it did not exist
+    reads: HashMap<String, Option<u64>>,
+    // Pending value of every key written, `None` if removed
+    writes: HashMap<String, Option<String>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Transaction<'a> {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let current = self.store.get_versioned(&key)?;
+        // A key read twice keeps the version of the first read, so that the commit
+        // fails if it changed in between
+        self.reads
+            .entry(key)
+            .or_insert(current.as_ref().map(|&(_, version)| version));
+        match current {
+            Some((value, _)) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key to a string on commit.
+    ///
+    /// Keys that are written without being read are not checked for conflicts.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a given key on commit.
+    ///
+    /// Returns an error if the key does not exist. This reads the key, so the
+    /// commit fails if it is written in the meantime.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Applies the writes of the transaction atomically, or fails with
+    /// `KvsError::TransactionConflict` if a key it read was written since.
+    pub fn commit(self) -> Result<()> {
+        self.store.commit(self.reads, self.writes)
+    }
+}
@@ -0,0 +1,85 @@
+use std::{any, marker::PhantomData};
+
+use failure::format_err;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{KvStore, KvsEngine, Result};
+
+/// A view of a `KvStore` holding values of type `T`, serialized as JSON.
+///
+/// Each typed store has a name and lives in the namespace of that name, see
+/// `KvStore::namespace`, so its keys are kept apart from those of the store and of
+/// other typed stores.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::KvStore;
+/// # use serde_derive::{Deserialize, Serialize};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// let users = store.typed::<User>("users").unwrap();
+/// let alice = User { name: "Alice".to_owned(), age: 30 };
+/// users.set("alice".to_owned(), &alice).unwrap();
+/// assert_eq!(users.get("alice".to_owned()).unwrap(), Some(alice));
+/// ```
+pub struct TypedStore<T> {
+    store: KvStore,
+    name: String,
+    value_type: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TypedStore<T> {
+    fn clone(&self) -> Self {
+        TypedStore {
+            store: self.store.clone(),
+            name: self.name.clone(),
+            value_type: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> TypedStore<T> {
+    pub(crate) fn new(store: KvStore, name: &str) -> TypedStore<T> {
+        TypedStore {
+            store,
+            name: name.to_owned(),
+            value_type: PhantomData,
+        }
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&self, key: String, value: &T) -> Result<()> {
+        self.store.set_bytes(key, serde_json::to_vec(value)?)
+    }
+
+    /// Gets the value of a key.
+    ///
+    /// Returns `None` if the key does not exist, and an error if its value is not
+    /// a valid `T`.
+    pub fn get(&self, key: String) -> Result<Option<T>> {
+        let Some(value) = self.store.get_bytes(key.clone())? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&value).map(Some).map_err(|err| {
+            format_err!(
+                "value of {:?} in {:?} is not a valid {}: {}",
+                key,
+                self.name,
+                any::type_name::<T>(),
+                err
+            )
+        })
+    }
+
+    /// Removes a key, failing with `KvsError::KeyNotFound` if it does not exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+}
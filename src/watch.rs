@@ -0,0 +1,58 @@
+use std::sync::mpsc::Sender;
+
+use crate::command::Command;
+
+/// A change to a watched key, see `KvStore::watch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The key was set to the value.
+    Set(String, Vec<u8>),
+    /// The key was removed.
+    Removed(String),
+}
+
+impl Event {
+    /// Key that changed.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set(key, _) | Event::Removed(key) => key,
+        }
+    }
+
+    pub(crate) fn from_command(cmd: &Command) -> Event {
+        match cmd {
+            Command::Set(key, value) | Command::SetExpiring(key, value, _) => {
+                Event::Set(key.clone(), value.clone())
+            }
+            Command::Rm(key) => Event::Removed(key.clone()),
+        }
+    }
+}
+
+/// A receiver of events on a key, or on all keys with a given prefix.
+pub(crate) struct Watcher {
+    pub(crate) prefix: String,
+    pub(crate) exact: bool,
+    pub(crate) sender: Sender<Event>,
+}
+
+impl Watcher {
+    fn matches(&self, key: &str) -> bool {
+        if self.exact {
+            key == self.prefix
+        } else {
+            key.starts_with(&self.prefix)
+        }
+    }
+}
+
+/// Sends the event of `cmd` to the watchers it concerns, dropping those whose
+/// receiver is gone.
+pub(crate) fn notify(watchers: &mut Vec<Watcher>, cmd: &Command) {
+    watchers.retain(|watcher| {
+        if !watcher.matches(cmd.key()) {
+            return true;
+        }
+        watcher.sender.send(Event::from_command(cmd)).is_ok()
+    });
+}
@@ -1,8 +1,19 @@
+// The CLI tests pass argument arrays by reference, as they always have
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    Change, Durability, Event, KvStore, KvsEngine, KvsError, MemoryKvsEngine, Options, Result,
+    SledKvsEngine, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{PredicateStrExt, contains, is_empty};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Bound;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +76,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -94,7 +105,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -117,6 +128,132 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs --engine sled` should store data with sled and keep using it afterwards.
+#[test]
+fn cli_sled_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// Data written by one engine should not be opened with another.
+#[test]
+fn cli_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs engine"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "unknown", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Data written by kvs before the engine was recorded should not be opened with sled.
+#[test]
+fn cli_wrong_engine_unrecorded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs engine"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `kvs --engine memory` should work without touching the directory.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    let entries = std::fs::read_dir(temp_dir.path()).unwrap().count();
+    assert_eq!(entries, 0);
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -181,7 +318,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +328,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +339,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +348,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +360,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,27 +376,89 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::KeyNotFound));
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// The sled engine should behave like `KvStore` behind the trait.
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: Box<dyn KvsEngine> = Box::new(SledKvsEngine::open(temp_dir.path())?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(store.remove("key2".to_owned()).is_ok());
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    // Open from disk again and check persistent data. Sled's background threads
+    // release the database lock shortly after it is dropped, not right away, so
+    // only that error is retried.
+    drop(store);
+    let mut attempts = 0;
+    let store = loop {
+        match SledKvsEngine::open(temp_dir.path()) {
+            Ok(store) => break store,
+            Err(err) if attempts < 50 && is_sled_lock_held(&err) => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Whether opening sled failed because another handle still holds its lock.
+fn is_sled_lock_held(err: &failure::Error) -> bool {
+    match err.downcast_ref::<sled::Error>() {
+        Some(sled::Error::Io(err)) => err.to_string().starts_with("could not acquire lock"),
+        _ => false,
+    }
+}
+
+// The in-memory engine should behave like `KvStore` behind the trait.
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemoryKvsEngine::new();
+    let other = store.clone();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    other.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_ok());
+    assert!(other.remove("key2".to_owned()).is_err());
+    assert_eq!(other.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -289,7 +488,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +498,1047 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should store and read back values that are much larger than a single read chunk.
+#[test]
+fn large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let large_key = "k".repeat(5_000);
+    let large_value = "v".repeat(50_000);
+    store.set(large_key.clone(), large_value.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get(large_key.clone())?, Some(large_value.clone()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(large_key)?, Some(large_value));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// An incomplete record left by a crash mid-write should be dropped on open.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Chop the last record in half.
+    let log_path = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log_path)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log_path)?;
+    file.set_len(len - 10)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The store stays writable and the tail is gone for good.
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should apply every operation of a batch, in order.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key3".to_owned());
+    batch.set("key2".to_owned(), "value4".to_owned());
+    store.write(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // A batch removing a missing key is rejected as a whole.
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value5".to_owned());
+    batch.remove("key1".to_owned());
+    assert!(store.write(batch).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped entirely.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value2".to_owned());
+    batch.set("key2".to_owned(), "value3".to_owned());
+    store.write(batch)?;
+    drop(store);
+
+    // Chop off the end of the batch, leaving its first operation intact.
+    let log_path = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log_path)?.len();
+    let file = std::fs::OpenOptions::new().write(true).open(&log_path)?;
+    file.set_len(len - 10)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A transaction should see its own writes and apply them all on commit.
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "10".to_owned())?;
+
+    let mut txn = store.begin();
+    let amount = txn.get("from".to_owned())?;
+    assert_eq!(amount, Some("10".to_owned()));
+    txn.remove("from".to_owned())?;
+    txn.set("to".to_owned(), "10".to_owned());
+    txn.set("temp".to_owned(), "value".to_owned());
+    txn.remove("temp".to_owned())?;
+    assert_eq!(txn.get("from".to_owned())?, None);
+    assert_eq!(txn.get("to".to_owned())?, Some("10".to_owned()));
+    assert!(txn.remove("missing".to_owned()).is_err());
+
+    // Nothing is visible before the commit.
+    assert_eq!(store.get("to".to_owned())?, None);
+    txn.commit()?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, None);
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("temp".to_owned())?, None);
+
+    Ok(())
+}
+
+// A transaction should fail to commit if a key it read was written meanwhile.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut first = store.begin();
+    let mut second = store.begin();
+    first.get("key1".to_owned())?;
+    second.get("key1".to_owned())?;
+    first.set("key1".to_owned(), "value2".to_owned());
+    second.set("key2".to_owned(), "value3".to_owned());
+    first.commit()?;
+    let err = second.commit().unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::TransactionConflict));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Writing the same value back still counts as a change.
+    let mut txn = store.begin();
+    txn.get("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    txn.set("key2".to_owned(), "value3".to_owned());
+    assert!(txn.commit().is_err());
+
+    // So does creating a key that was missing.
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    txn.set("key3".to_owned(), "value5".to_owned());
+    assert!(txn.commit().is_err());
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should list key/value pairs in key order within a range.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key4".to_owned())?;
+
+    let scan = |range: (Bound<String>, Bound<String>)| -> Result<Vec<String>> {
+        store
+            .scan(range)
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    };
+    let keys = |ids: &[u32]| -> Vec<String> { ids.iter().map(|id| format!("key{}", id)).collect() };
+
+    assert_eq!(
+        scan((
+            Bound::Included("key2".to_owned()),
+            Bound::Excluded("key6".to_owned())
+        ))?,
+        keys(&[2, 3, 5])
+    );
+    assert_eq!(
+        scan((
+            Bound::Excluded("key2".to_owned()),
+            Bound::Included("key6".to_owned())
+        ))?,
+        keys(&[3, 5, 6])
+    );
+    assert_eq!(
+        scan((Bound::Unbounded, Bound::Unbounded))?,
+        keys(&[0, 1, 2, 3, 5, 6, 7, 8, 9])
+    );
+    assert!(
+        scan((
+            Bound::Included("key6".to_owned()),
+            Bound::Excluded("key2".to_owned())
+        ))?
+        .is_empty()
+    );
+    assert!(
+        scan((
+            Bound::Excluded("key6".to_owned()),
+            Bound::Excluded("key6".to_owned())
+        ))?
+        .is_empty()
+    );
+
+    let pairs: Vec<(String, String)> = store.scan("key8".to_owned()..).collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key8".to_owned(), "value8".to_owned()),
+            ("key9".to_owned(), "value9".to_owned())
+        ]
+    );
+
+    Ok(())
+}
+
+// Should list the subtree of hierarchical keys sharing a prefix.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in [
+        "svc/eu/api",
+        "svc/us/web",
+        "svc/eu/web",
+        "svc/eu-west/api",
+        "svc/us/api",
+        "svd/eu/api",
+    ] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<(String, String)> = store.scan_prefix("svc/eu/").collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("svc/eu/api".to_owned(), "SVC/EU/API".to_owned()),
+            ("svc/eu/web".to_owned(), "SVC/EU/WEB".to_owned())
+        ]
+    );
+    assert_eq!(store.scan_prefix("svc/").count(), 5);
+    assert_eq!(store.scan_prefix("").count(), 6);
+    assert_eq!(store.scan_prefix("svc/asia/").count(), 0);
+
+    Ok(())
+}
+
+// A snapshot should keep seeing the store as it was when taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("svc/a".to_owned(), "value1".to_owned())?;
+    store.set("svc/b".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot();
+
+    store.set("svc/a".to_owned(), "value3".to_owned())?;
+    store.remove("svc/b".to_owned())?;
+    store.set("svc/c".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("svc/a".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("svc/b".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("svc/c".to_owned())?, None);
+    let pairs: Vec<(String, String)> = snapshot.scan_prefix("svc/").collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("svc/a".to_owned(), "value1".to_owned()),
+            ("svc/b".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(snapshot.scan("svc/b".to_owned()..).count(), 1);
+
+    // The store itself moved on.
+    assert_eq!(store.get("svc/a".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.scan_prefix("svc/").count(), 2);
+
+    // A snapshot can be read from another thread, after the store is gone.
+    drop(store);
+    let handle = thread::spawn(move || snapshot.get("svc/a".to_owned()));
+    assert_eq!(
+        handle.join().expect("thread panicked")?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Keys set with a TTL should read as absent once it has elapsed, also after reopening.
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "session/a".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "session/b".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("session/c".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.get("session/a".to_owned())?,
+        Some("value1".to_owned())
+    );
+    let snapshot = store.snapshot();
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("session/a".to_owned())?, None);
+    // The snapshot keeps the value that was live when it was taken
+    assert_eq!(
+        snapshot.get("session/a".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(snapshot.scan_prefix("session/").count(), 3);
+    assert_eq!(store.scan_prefix("session/").count(), 2);
+    assert_eq!(
+        store
+            .remove("session/a".to_owned())
+            .unwrap_err()
+            .downcast_ref(),
+        Some(&KvsError::KeyNotFound)
+    );
+
+    // A plain set clears the TTL
+    store.set_with_ttl(
+        "session/c".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("session/c".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(300));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session/a".to_owned())?, None);
+    assert_eq!(
+        store.get("session/b".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("session/c".to_owned())?,
+        Some("value5".to_owned())
+    );
+
+    Ok(())
+}
+
+// Conditional writes should only apply when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(store.set_if_present("key1".to_owned(), "value3".to_owned())?);
+    assert!(!store.set_if_present("key2".to_owned(), "value4".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value5".to_owned())
+    )?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value3".to_owned()),
+        Some("value5".to_owned())
+    )?);
+    assert!(store.compare_and_swap("key1".to_owned(), Some("value5".to_owned()), None)?);
+    assert!(store.compare_and_swap("key2".to_owned(), None, Some("value6".to_owned()))?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value6".to_owned()));
+
+    // Threads racing to bump a counter never lose an update.
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let next = current
+                            .as_deref()
+                            .map_or(0, |value| value.parse::<u64>().unwrap() + 1);
+                        if store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next.to_string()),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+
+    Ok(())
+}
+
+// Counters should add up, including from several threads, and reject other values.
+#[test]
+fn counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 3)?, 3);
+    assert_eq!(store.decr("counter".to_owned(), 5)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("name".to_owned(), "value".to_owned())?;
+    let err = store.incr("name".to_owned(), 1).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::NotAnInteger));
+    assert_eq!(store.get("name".to_owned())?, Some("value".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    let err = store.incr("max".to_owned(), 1).unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&KvsError::CounterOverflow));
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    store.incr("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("98".to_owned()));
+
+    Ok(())
+}
+
+// Binary values should be stored as they are, and strings readable as bytes.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = vec![0, 255, b'\n', b'"', 128];
+    store.set_bytes("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_bytes("key3".to_owned(), vec![])?;
+
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(store.get_bytes("key4".to_owned())?, None);
+    // Values that are not UTF-8 cannot be read as strings
+    assert!(store.get("key1".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some(String::new()));
+
+    // Scans list binary values as bytes, and go on past them as strings
+    let keys: Vec<String> = store
+        .scan_prefix_bytes("key")
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["key1", "key2", "key3"]);
+    let pairs: Vec<Option<String>> = store
+        .scan_prefix("key")
+        .map(|pair| pair.ok().map(|(key, _)| key))
+        .collect();
+    assert_eq!(
+        pairs,
+        [None, Some("key2".to_owned()), Some("key3".to_owned())]
+    );
+    let snapshot = store.snapshot();
+    assert_eq!(
+        snapshot.scan_bytes(..).next().transpose()?,
+        Some(("key1".to_owned(), value.clone()))
+    );
+    assert_eq!(snapshot.get_bytes("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Typed stores should round-trip values in their own keyspace.
+#[test]
+fn typed_store() -> Result<()> {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.typed::<User>("users")?;
+    let counts = store.typed::<u64>("counts")?;
+    assert!(store.typed::<u64>("").is_err());
+    assert!(store.typed::<u64>("users/admins").is_err());
+    let user = User {
+        name: "Alice".to_owned(),
+        tags: vec!["admin".to_owned()],
+    };
+    users.set("1".to_owned(), &user)?;
+    counts.set("1".to_owned(), &42)?;
+
+    assert_eq!(users.get("1".to_owned())?.as_ref(), Some(&user));
+    assert_eq!(counts.get("1".to_owned())?, Some(42));
+    assert_eq!(users.get("2".to_owned())?, None);
+
+    // Typed keys are kept out of the plain keyspace
+    store.set("users/1".to_owned(), "plain".to_owned())?;
+    assert_eq!(store.get("1".to_owned())?, None);
+    assert_eq!(store.stats().keys, 1);
+    assert_eq!(store.scan_prefix("").count(), 1);
+    assert_eq!(users.get("1".to_owned())?, Some(user));
+
+    // A value of another type is reported, not misread
+    store
+        .namespace("users")?
+        .set("2".to_owned(), "not a user".to_owned())?;
+    let err = users.get("2".to_owned()).unwrap_err();
+    assert!(err.to_string().contains("is not a valid"), "{}", err);
+
+    users.remove("1".to_owned())?;
+    assert_eq!(users.get("1".to_owned())?, None);
+    assert_eq!(counts.get("1".to_owned())?, Some(42));
+
+    Ok(())
+}
+
+// Namespaces should hold their own keys, persist, and be dropped as a whole.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "root".to_owned())?;
+    let users = store.namespace("users")?;
+    let orders = store.namespace("orders")?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    users.set("key2".to_owned(), "user".to_owned())?;
+    orders.set("key1".to_owned(), "order".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key2".to_owned())?, None);
+    assert_eq!(store.stats().keys, 1);
+    assert_eq!(users.stats().keys, 2);
+    assert_eq!(orders.stats().keys, 1);
+    assert!(store.namespace("a/b").is_err());
+    drop(users);
+    drop(orders);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key2".to_owned())?, Some("user".to_owned()));
+
+    store.drop_namespace("users")?;
+    // Handles opened before see the namespace empty and cannot write to it
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert!(users.set("key3".to_owned(), "user".to_owned()).is_err());
+    assert_eq!(store.namespace("users")?.stats().keys, 0);
+    assert_eq!(
+        store.namespace("orders")?.get("key1".to_owned())?,
+        Some("order".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    store.drop_namespace("missing")?;
+
+    Ok(())
+}
+
+// Watchers should receive the writes to their key or prefix, in order.
+#[test]
+fn watch_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key_events = store.watch("svc/a".to_owned());
+    let prefix_events = store.watch_prefix("svc/");
+    let dropped_events = store.watch_prefix("");
+    drop(dropped_events);
+
+    let writer = store.clone();
+    thread::spawn(move || -> Result<()> {
+        writer.set("svc/a".to_owned(), "value1".to_owned())?;
+        writer.set("other".to_owned(), "value2".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("svc/b".to_owned(), "value3".to_owned());
+        batch.remove("svc/a".to_owned());
+        writer.write(batch)
+    })
+    .join()
+    .expect("thread panicked")?;
+
+    assert_eq!(
+        key_events.try_iter().collect::<Vec<_>>(),
+        vec![
+            Event::Set("svc/a".to_owned(), b"value1".to_vec()),
+            Event::Removed("svc/a".to_owned()),
+        ]
+    );
+    let keys: Vec<String> = prefix_events
+        .try_iter()
+        .map(|event| event.key().to_owned())
+        .collect();
+    assert_eq!(keys, ["svc/a", "svc/b", "svc/a"]);
+
+    // Failed writes send nothing
+    assert!(store.remove("svc/c".to_owned()).is_err());
+    assert!(prefix_events.try_recv().is_err());
+
+    Ok(())
+}
+
+// The change stream should list the writes in order, and resume from a cursor
+// across restarts and compactions.
+#[test]
+fn change_stream() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        segment_size: 256,
+        compaction_min_stale_bytes: 512,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let mut changes = store.changes(0);
+    let events: Vec<Event> = changes
+        .by_ref()
+        .map(|change| change.map(|change| change.event))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        events,
+        vec![
+            Event::Set("key1".to_owned(), b"value1".to_vec()),
+            Event::Set("key2".to_owned(), b"value2".to_vec()),
+            Event::Removed("key1".to_owned()),
+        ]
+    );
+    let cursor = changes.cursor();
+    drop(changes);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let changes: Vec<Change> = store.changes(cursor).collect::<Result<_>>()?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].event,
+        Event::Set("key3".to_owned(), b"value3".to_vec())
+    );
+
+    // Compaction runs while the writes are read, the latest one of each key stays.
+    let mut changes = store.changes(changes[0].seq + 1);
+    for round in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+    }
+    let mut latest = HashMap::new();
+    let mut last_seq = None;
+    for change in changes.by_ref() {
+        let change = change?;
+        assert!(Some(change.seq) > last_seq);
+        last_seq = Some(change.seq);
+        if let Event::Set(key, value) = change.event {
+            latest.insert(key, value);
+        }
+    }
+    for key_id in 0..10 {
+        assert_eq!(latest[&format!("key{}", key_id)], b"value49");
+    }
+
+    // A reader that caught up picks up later writes
+    assert!(changes.next().is_none());
+    store.set("key0".to_owned(), "last".to_owned())?;
+    assert_eq!(
+        changes.next().transpose()?.map(|change| change.event),
+        Some(Event::Set("key0".to_owned(), b"last".to_vec()))
+    );
+
+    Ok(())
+}
+
+// A backup should hold the live keys of the store, namespaces included, while
+// writes go on.
+#[test]
+fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::create_dir(temp_dir.path().join("store"))?;
+    let store = KvStore::open_with_options(
+        temp_dir.path().join("store"),
+        Options {
+            segment_size: 1024,
+            ..Options::default()
+        },
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store
+        .namespace("users")?
+        .set("key1".to_owned(), "user".to_owned())?;
+    for name in ["a", "a.tmp"] {
+        store
+            .namespace(name)?
+            .set("key".to_owned(), name.to_owned())?;
+    }
+    // Not the backup's to touch
+    std::fs::create_dir(temp_dir.path().join("backup.tmp"))?;
+    std::fs::write(temp_dir.path().join("backup.tmp").join("precious"), b"data")?;
+    thread::sleep(Duration::from_millis(10));
+
+    let writer = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for iter in 0..500 {
+            writer.set(format!("key{}", iter % 100), "new".to_owned())?;
+        }
+        Ok(())
+    });
+    store.backup(temp_dir.path().join("backup"))?;
+    handle.join().expect("thread panicked")?;
+    assert!(store.backup(temp_dir.path().join("backup")).is_err());
+    drop(store);
+
+    let backup = KvStore::open(temp_dir.path().join("backup"))?;
+    assert_eq!(backup.get("key0".to_owned())?, None);
+    assert_eq!(backup.get("session".to_owned())?, None);
+    for key_id in 1..100 {
+        let value = backup.get(format!("key{}", key_id))?;
+        assert!(
+            value == Some(format!("value{}", key_id)) || value == Some("new".to_owned()),
+            "{:?}",
+            value
+        );
+    }
+    assert_eq!(backup.stats().keys, 99);
+    // Keys were not written in key order, the copy keeps them in write order
+    let seqs: Vec<u64> = backup
+        .changes(0)
+        .map(|change| change.map(|change| change.seq))
+        .collect::<Result<_>>()?;
+    assert_eq!(seqs.len(), 99);
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+    assert_eq!(
+        backup.namespace("users")?.get("key1".to_owned())?,
+        Some("user".to_owned())
+    );
+    for name in ["a", "a.tmp"] {
+        assert_eq!(
+            backup.namespace(name)?.get("key".to_owned())?,
+            Some(name.to_owned())
+        );
+    }
+    assert!(temp_dir.path().join("backup.tmp").join("precious").exists());
+    let mut entries: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    entries.sort();
+    assert_eq!(entries, ["backup", "backup.tmp", "store"]);
+
+    Ok(())
+}
+
+// A compacted file left behind by a crash before the rename should be discarded.
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let compact_path = temp_dir.path().join("1.log.compact");
+    std::fs::write(&compact_path, b"partially written")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!compact_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // With the segment gone, the compacted file is moved into place.
+    drop(store);
+    let log_path = temp_dir.path().join("1.log");
+    std::fs::rename(&log_path, &compact_path)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!compact_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Every durability mode should persist writes across reopen.
+#[test]
+fn durability_modes() -> Result<()> {
+    let modes = [
+        Durability::Always,
+        Durability::Periodic(Duration::from_millis(5)),
+        Durability::Never,
+    ];
+    for durability in modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            durability,
+            ..Options::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            thread::sleep(Duration::from_millis(1));
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Writes should spread over several segments and survive reopening and compaction.
+#[test]
+fn segmented_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        segment_size: 1024,
+        ..Options::default()
+    };
+    let segment_count = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "log")
+            })
+            .count()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(segment_count() > 1);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        let expected = (key_id >= 50).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Should only compact once enough of the log is stale, not merely because it grew.
+#[test]
+fn compaction_thresholds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        segment_size: 1024,
+        compaction_min_stale_bytes: 4096,
+        compaction_stale_ratio: 0.5,
+        ..Options::default()
+    };
+    let segment_count = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "log")
+            })
+            .count()
+    };
+
+    // Distinct keys leave nothing stale however large the log grows
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let live_segments = segment_count();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(segment_count(), live_segments);
+
+    // Overwriting them makes most of the log stale, which compaction drops
+    for iter in 0..3 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let mut attempts = 0;
+    while segment_count() > 2 * live_segments {
+        attempts += 1;
+        assert!(attempts < 100, "No compaction detected");
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value2".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Clones of a store should be usable from many threads at once.
+#[test]
+fn concurrent_access() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+    assert_shareable::<KvStore>();
+    assert_shareable::<SledKvsEngine>();
+    assert_shareable::<MemoryKvsEngine>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        segment_size: 1024,
+        compaction_min_stale_bytes: 4096,
+        ..Options::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("shared".to_owned(), "value".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..5 {
+                    for key_id in 0..50 {
+                        let key = format!("key{}-{}", thread_id, key_id);
+                        store.set(key.clone(), format!("value{}", iter))?;
+                        assert_eq!(store.get(key)?, Some(format!("value{}", iter)));
+                        assert_eq!(store.get("shared".to_owned())?, Some("value".to_owned()));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some("value4".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// A store written before the log was split into segments should still open.
+#[test]
+fn migrate_head_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::rename(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("head.log"),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("head.log").exists());
+    Ok(())
+}